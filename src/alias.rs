@@ -0,0 +1,155 @@
+use std::{fmt, str::FromStr};
+
+use crate::{filter, Device};
+
+/// A friendly display name and icon for a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alias {
+    pub name: String,
+    pub icon: Option<String>,
+}
+
+/// Maps a serial, Bluetooth address or model to an alias, in the form KEY=NAME or KEY=NAME|ICON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AliasRule {
+    key: String,
+    alias: Alias,
+}
+
+impl AliasRule {
+    pub fn new(key: String, alias: Alias) -> Self {
+        Self { key, alias }
+    }
+
+    /// Whether the key identifies this specific device, rather than any device of the same model.
+    fn matches_identity(&self, device: &Device) -> bool {
+        (!device.serial.is_empty() && self.key.eq_ignore_ascii_case(&device.serial))
+            || filter::address(device)
+                .is_some_and(|address| self.key.eq_ignore_ascii_case(&address))
+    }
+}
+
+/// Returns the alias for a device, if any rule matches it. Rules matching the serial or address
+/// take precedence over rules matching the model; otherwise, the first matching rule wins.
+pub fn resolve(rules: &[AliasRule], device: &Device) -> Option<Alias> {
+    rules
+        .iter()
+        .find(|rule| rule.matches_identity(device))
+        .or_else(|| rules.iter().find(|rule| rule.key == device.model))
+        .map(|rule| rule.alias.clone())
+}
+
+impl FromStr for AliasRule {
+    type Err = AliasError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| AliasError::MissingSeparator(s.to_string()))?;
+
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(AliasError::EmptyKey(s.to_string()));
+        }
+
+        let (name, icon) = match value.rsplit_once('|') {
+            Some((name, icon)) => (name, Some(icon.trim().to_string())),
+            None => (value, None),
+        };
+
+        Ok(Self {
+            key: key.to_string(),
+            alias: Alias {
+                name: name.trim().to_string(),
+                icon: icon.filter(|icon| !icon.is_empty()),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AliasError {
+    MissingSeparator(String),
+    EmptyKey(String),
+}
+
+impl fmt::Display for AliasError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingSeparator(term) => {
+                write!(
+                    f,
+                    "alias {term:?} must be in the form KEY=NAME or KEY=NAME|ICON"
+                )
+            }
+            Self::EmptyKey(term) => write!(f, "alias {term:?} has an empty key"),
+        }
+    }
+}
+
+impl std::error::Error for AliasError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> AliasRule {
+        AliasRule::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            rule(" WH-1000XM4 = Sony headphones | 🎧 "),
+            AliasRule {
+                key: "WH-1000XM4".to_string(),
+                alias: Alias {
+                    name: "Sony headphones".to_string(),
+                    icon: Some("🎧".to_string()),
+                },
+            }
+        );
+        assert_eq!(rule("x=y").alias.icon, None);
+        assert_eq!(rule("x=y|").alias.icon, None);
+
+        assert_eq!(
+            AliasRule::from_str("headset"),
+            Err(AliasError::MissingSeparator("headset".to_string()))
+        );
+        assert_eq!(
+            AliasRule::from_str("=headset"),
+            Err(AliasError::EmptyKey("=headset".to_string()))
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        let rules = [
+            rule("WH-1000XM4=Model"),
+            rule("aa:bb:cc:dd:ee:ff=Address"),
+            rule("12345=Serial"),
+        ];
+        let name = |device: &Device| resolve(&rules, device).map(|alias| alias.name);
+
+        let device = Device {
+            model: "WH-1000XM4".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(name(&device).as_deref(), Some("Model"));
+
+        let device = Device {
+            native_path: "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF".to_string(),
+            ..device
+        };
+        assert_eq!(name(&device).as_deref(), Some("Address"));
+
+        let device = Device {
+            native_path: String::new(),
+            serial: "12345".to_string(),
+            ..device
+        };
+        assert_eq!(name(&device).as_deref(), Some("Serial"));
+
+        assert_eq!(name(&Device::default()), None);
+    }
+}
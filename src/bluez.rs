@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+
+use tokio::select;
+use tokio_stream::StreamExt;
+use zbus::{
+    fdo::{DBusProxy, NameOwnerChangedStream, ObjectManagerProxy, PropertiesProxy},
+    names::InterfaceName,
+    zvariant::{OwnedObjectPath, OwnedValue, Value},
+    Connection, MatchRule, MessageStream, MessageType,
+};
+
+use crate::{
+    source::{is_missing, DeviceSource, Event},
+    Device, DeviceKind, DeviceState,
+};
+
+const SERVICE: &str = "org.bluez";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const BATTERY_INTERFACE: &str = "org.bluez.Battery1";
+
+/// The properties of org.bluez.Device1 that snapshots depend on. Others, such as RSSI and
+/// ManufacturerData, change constantly for every nearby device while the adapter is scanning.
+const DEVICE_PROPERTIES: [&str; 5] = ["Connected", "Alias", "Name", "Icon", "Class"];
+
+/// Reads connected BlueZ devices that expose a battery.
+///
+/// BlueZ reports everything through its object manager, so a single stream of every signal it
+/// sends is enough to notice devices connecting, disconnecting and changing. Signals that can't
+/// change a snapshot are ignored.
+pub struct BluezSource {
+    conn: Connection,
+    objects: ObjectManagerProxy<'static>,
+    signals: MessageStream,
+    owner_changed: NameOwnerChangedStream<'static>,
+}
+
+impl BluezSource {
+    pub async fn new(conn: &Connection) -> anyhow::Result<Self> {
+        let objects = ObjectManagerProxy::builder(conn)
+            .destination(SERVICE)?
+            .path("/")?
+            .build()
+            .await?;
+        let rule = MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .sender(SERVICE)?
+            .build();
+
+        Ok(Self {
+            conn: conn.clone(),
+            objects,
+            signals: MessageStream::for_match_rule(rule, conn, None).await?,
+            owner_changed: DBusProxy::new(conn)
+                .await?
+                .receive_name_owner_changed_with_args(&[(0, SERVICE)])
+                .await?,
+        })
+    }
+
+    /// Returns all properties on the given interface, or `None` if the object or interface
+    /// doesn't exist.
+    async fn properties(
+        &self,
+        properties: &PropertiesProxy<'_>,
+        interface: &'static str,
+    ) -> anyhow::Result<Option<HashMap<String, OwnedValue>>> {
+        match properties
+            .get_all(InterfaceName::from_static_str(interface)?)
+            .await
+            .map_err(zbus::Error::from)
+        {
+            Ok(values) => Ok(Some(values)),
+            Err(e) if is_missing(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl DeviceSource for BluezSource {
+    async fn enumerate(&self) -> anyhow::Result<Vec<String>> {
+        let mut ids: Vec<_> = self
+            .objects
+            .get_managed_objects()
+            .await?
+            .into_iter()
+            .filter(|(_, interfaces)| {
+                interfaces.contains_key(DEVICE_INTERFACE)
+                    && interfaces.contains_key(BATTERY_INTERFACE)
+            })
+            .map(|(path, _)| path.to_string())
+            .collect();
+
+        // GetManagedObjects returns a map, so we sort to keep the order stable.
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// The device's Bluetooth address is used as the serial, and its object path as the native
+    /// path, which is consistent with how upower reports BlueZ devices.
+    async fn snapshot(&self, id: &str) -> anyhow::Result<Option<Device>> {
+        let proxy = PropertiesProxy::builder(&self.conn)
+            .destination(SERVICE)?
+            .path(OwnedObjectPath::try_from(id)?)?
+            .build()
+            .await?;
+
+        let (Some(device), Some(battery)) = (
+            self.properties(&proxy, DEVICE_INTERFACE).await?,
+            self.properties(&proxy, BATTERY_INTERFACE).await?,
+        ) else {
+            return Ok(None);
+        };
+
+        if !property::<bool>(&device, "Connected").unwrap_or_default() {
+            return Ok(None);
+        }
+
+        let icon_name = property::<String>(&device, "Icon").unwrap_or_default();
+        Ok(Some(Device {
+            kind: kind_from_icon(&icon_name)
+                .or_else(|| property::<u32>(&device, "Class").and_then(kind_from_class))
+                .unwrap_or_default(),
+            model: property::<String>(&device, "Alias")
+                .or_else(|| property::<String>(&device, "Name"))
+                .unwrap_or_default(),
+            vendor: String::new(),
+            serial: property::<String>(&device, "Address").unwrap_or_default(),
+            native_path: id.to_string(),
+            icon_name,
+            percentage: property::<u8>(&battery, "Percentage")
+                .unwrap_or_default()
+                .into(),
+            state: DeviceState::Unknown,
+            time_to_empty: None,
+            time_to_full: None,
+            alias: None,
+            estimate: None,
+            sparkline: None,
+        }))
+    }
+
+    async fn next_event(&mut self) -> anyhow::Result<Event> {
+        loop {
+            let message = select! {
+                Some(message) = self.signals.next() => message?,
+                Some(_) = self.owner_changed.next() => return Ok(Event::Reset),
+                else => anyhow::bail!("lost connection to BlueZ"),
+            };
+            let header = message.header()?;
+
+            // Interface changes are signalled on the object manager, with the device path as the
+            // first argument; everything else is signalled on the device itself.
+            let path = match header.member()?.map(|member| member.as_str()) {
+                Some("InterfacesAdded") => {
+                    message
+                        .body::<(
+                            OwnedObjectPath,
+                            HashMap<String, HashMap<String, OwnedValue>>,
+                        )>()?
+                        .0
+                }
+                Some("InterfacesRemoved") => message.body::<(OwnedObjectPath, Vec<String>)>()?.0,
+                Some("PropertiesChanged") => {
+                    let (interface, changed, invalidated) =
+                        message.body::<(String, HashMap<String, OwnedValue>, Vec<String>)>()?;
+                    let names = changed.keys().chain(invalidated.iter());
+                    match (affects_snapshot(&interface, names), header.path()?) {
+                        (true, Some(path)) => path.to_owned().into(),
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+
+            return Ok(Event::Changed(path.to_string()));
+        }
+    }
+}
+
+/// Whether a change to the given properties of an interface could change a device's snapshot.
+fn affects_snapshot<'a>(interface: &str, mut names: impl Iterator<Item = &'a String>) -> bool {
+    match interface {
+        BATTERY_INTERFACE => true,
+        DEVICE_INTERFACE => names.any(|name| DEVICE_PROPERTIES.contains(&name.as_str())),
+        _ => false,
+    }
+}
+
+fn property<T>(properties: &HashMap<String, OwnedValue>, name: &str) -> Option<T>
+where
+    T: TryFrom<Value<'static>>,
+{
+    properties
+        .get(name)
+        .and_then(|value| T::try_from(Value::from(value.clone())).ok())
+}
+
+/// Maps the freedesktop icon name BlueZ derives for a device to a kind, following upower's own
+/// mapping where possible.
+fn kind_from_icon(icon: &str) -> Option<DeviceKind> {
+    Some(match icon {
+        "audio-headset" => DeviceKind::Headset,
+        "audio-headphones" => DeviceKind::Headphones,
+        "audio-card" => DeviceKind::Speakers,
+        "camera-photo" | "camera-video" => DeviceKind::Camera,
+        "computer" => DeviceKind::Computer,
+        "input-gaming" => DeviceKind::GamingInput,
+        "input-keyboard" => DeviceKind::Keyboard,
+        "input-mouse" => DeviceKind::Mouse,
+        "input-tablet" => DeviceKind::Tablet,
+        "modem" => DeviceKind::Modem,
+        "network-wireless" => DeviceKind::Network,
+        "phone" => DeviceKind::Phone,
+        "printer" => DeviceKind::Printer,
+        "scanner" => DeviceKind::Scanner,
+        "video-display" => DeviceKind::Video,
+        _ => return None,
+    })
+}
+
+/// Maps a Bluetooth class of device to a kind, for devices that don't have an icon.
+fn kind_from_class(class: u32) -> Option<DeviceKind> {
+    let major = (class >> 8) & 0x1f;
+    let minor = (class >> 2) & 0x3f;
+
+    Some(match major {
+        0x01 => DeviceKind::Computer,
+        0x02 => DeviceKind::Phone,
+        0x03 => DeviceKind::Network,
+        0x04 => match minor {
+            0x01 | 0x02 => DeviceKind::Headset,
+            0x05 => DeviceKind::Speakers,
+            0x06 => DeviceKind::Headphones,
+            0x0b..=0x0f => DeviceKind::Video,
+            _ => DeviceKind::OtherAudio,
+        },
+        0x05 => match (minor >> 4, minor & 0x0f) {
+            (_, 0x01 | 0x02) => DeviceKind::GamingInput,
+            (_, 0x05) => DeviceKind::Tablet,
+            (0x01, _) => DeviceKind::Keyboard,
+            (0x02, _) => DeviceKind::Mouse,
+            _ => return None,
+        },
+        0x06 => match minor {
+            minor if minor & 0x20 != 0 => DeviceKind::Printer,
+            minor if minor & 0x10 != 0 => DeviceKind::Scanner,
+            minor if minor & 0x08 != 0 => DeviceKind::Camera,
+            _ => DeviceKind::Monitor,
+        },
+        0x07 => DeviceKind::Wearable,
+        0x08 => DeviceKind::Toy,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relevant_changes() {
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(affects_snapshot(
+            BATTERY_INTERFACE,
+            names(&["Percentage"]).iter()
+        ));
+        assert!(affects_snapshot(
+            DEVICE_INTERFACE,
+            names(&["RSSI", "Connected"]).iter()
+        ));
+        assert!(!affects_snapshot(
+            DEVICE_INTERFACE,
+            names(&["RSSI", "ManufacturerData"]).iter()
+        ));
+        assert!(!affects_snapshot(
+            "org.bluez.MediaControl1",
+            names(&["Connected"]).iter()
+        ));
+    }
+
+    #[test]
+    fn icons() {
+        assert_eq!(kind_from_icon("audio-headset"), Some(DeviceKind::Headset));
+        assert_eq!(
+            kind_from_icon("audio-headphones"),
+            Some(DeviceKind::Headphones)
+        );
+        assert_eq!(kind_from_icon("camera-video"), Some(DeviceKind::Camera));
+        assert_eq!(
+            kind_from_icon("input-gaming"),
+            Some(DeviceKind::GamingInput)
+        );
+        assert_eq!(kind_from_icon("bluetooth"), None);
+    }
+
+    #[test]
+    fn audio_classes() {
+        // Real classes, with service bits set.
+        assert_eq!(kind_from_class(0x240404), Some(DeviceKind::Headset));
+        assert_eq!(kind_from_class(0x200408), Some(DeviceKind::Headset));
+        assert_eq!(kind_from_class(0x240414), Some(DeviceKind::Speakers));
+        assert_eq!(kind_from_class(0x240418), Some(DeviceKind::Headphones));
+        assert_eq!(kind_from_class(0x24043c), Some(DeviceKind::Video));
+        // A car kit.
+        assert_eq!(kind_from_class(0x240420), Some(DeviceKind::OtherAudio));
+    }
+
+    #[test]
+    fn peripheral_classes() {
+        assert_eq!(kind_from_class(0x002540), Some(DeviceKind::Keyboard));
+        assert_eq!(kind_from_class(0x002580), Some(DeviceKind::Mouse));
+        assert_eq!(kind_from_class(0x002508), Some(DeviceKind::GamingInput));
+        assert_eq!(kind_from_class(0x002514), Some(DeviceKind::Tablet));
+        // A keyboard with a touchpad is neither one nor the other, unless it's also a gamepad.
+        assert_eq!(kind_from_class(0x0025c0), None);
+        assert_eq!(kind_from_class(0x0025c8), Some(DeviceKind::GamingInput));
+    }
+
+    #[test]
+    fn imaging_classes() {
+        assert_eq!(kind_from_class(0x000610), Some(DeviceKind::Monitor));
+        assert_eq!(kind_from_class(0x000620), Some(DeviceKind::Camera));
+        assert_eq!(kind_from_class(0x000640), Some(DeviceKind::Scanner));
+        assert_eq!(kind_from_class(0x000680), Some(DeviceKind::Printer));
+        // Several flags can be set at once, such as for a printer that scans.
+        assert_eq!(kind_from_class(0x0006c0), Some(DeviceKind::Printer));
+        assert_eq!(kind_from_class(0x000630), Some(DeviceKind::Camera));
+    }
+
+    #[test]
+    fn other_classes() {
+        assert_eq!(kind_from_class(0x5a020c), Some(DeviceKind::Phone));
+        assert_eq!(kind_from_class(0x00010c), Some(DeviceKind::Computer));
+        assert_eq!(kind_from_class(0x000704), Some(DeviceKind::Wearable));
+        assert_eq!(kind_from_class(0x000000), None);
+        assert_eq!(kind_from_class(0x001f00), None);
+    }
+}
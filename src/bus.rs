@@ -0,0 +1,36 @@
+use std::{convert::Infallible, str::FromStr};
+
+use zbus::{Connection, ConnectionBuilder};
+
+/// The D-Bus bus to connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bus {
+    /// The system bus, which respects `DBUS_SYSTEM_BUS_ADDRESS`.
+    System,
+    /// The session bus, which respects `DBUS_SESSION_BUS_ADDRESS`.
+    Session,
+    /// An explicit bus address, such as `unix:path=/run/dbus/system_bus_socket`.
+    Address(String),
+}
+
+impl Bus {
+    pub async fn connect(&self) -> zbus::Result<Connection> {
+        match self {
+            Self::System => Connection::system().await,
+            Self::Session => Connection::session().await,
+            Self::Address(address) => ConnectionBuilder::address(address.as_str())?.build().await,
+        }
+    }
+}
+
+impl FromStr for Bus {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "system" => Self::System,
+            "session" => Self::Session,
+            address => Self::Address(address.to_string()),
+        })
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use clap::{parser::ValueSource, ArgMatches};
+use serde::Deserialize;
+
+use crate::{
+    alias::{Alias, AliasRule},
+    Opt,
+};
+
+/// The options that can be set in the configuration file. Values use the same syntax as the
+/// corresponding command line options, and are overridden by them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    kinds: Option<Terms>,
+    states: Option<Terms>,
+    full_state: Option<String>,
+    listen: Option<bool>,
+    backend: Option<String>,
+    bus: Option<String>,
+    refresh: Option<String>,
+    heartbeat: Option<String>,
+    output: Option<String>,
+    colors: Option<Terms>,
+    cycle_signal: Option<u8>,
+    aggregate: Option<String>,
+    separator: Option<String>,
+    format: Option<String>,
+    tooltip_format: Option<String>,
+    on_error: Option<String>,
+    error_text: Option<String>,
+    notify: Option<bool>,
+    notify_charged: Option<bool>,
+    on_threshold: Option<String>,
+    on_connect: Option<String>,
+    on_disconnect: Option<String>,
+    on_charged: Option<String>,
+    hook_timeout: Option<String>,
+    hook_interval: Option<String>,
+    no_history: Option<bool>,
+    sparkline_window: Option<String>,
+    #[serde(rename = "match")]
+    matches: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    /// Named after --alias, like every other key, but `aliases` reads better for a table.
+    #[serde(alias = "alias")]
+    aliases: Option<BTreeMap<String, AliasEntry>>,
+}
+
+/// A comma separated list, which may also be written as an array.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Terms {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Terms {
+    fn join(self) -> String {
+        match self {
+            Self::One(terms) => terms,
+            Self::Many(terms) => terms.join(","),
+        }
+    }
+}
+
+/// An alias, written either as just a name, or as a table with a name and an icon.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AliasEntry {
+    Name(String),
+    Full(AliasTable),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AliasTable {
+    name: String,
+    icon: Option<String>,
+}
+
+impl Config {
+    /// The configuration file used if --config isn't given.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(
+            dirs::config_dir()?
+                .join(env!("CARGO_PKG_NAME"))
+                .join("config.toml"),
+        )
+    }
+
+    /// Reads the configuration file at the given path, or the default path if there isn't one.
+    /// It's only an error for the default file not to exist if it was asked for explicitly.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(e) => return Err(e).with_context(|| format!("error reading {}", path.display())),
+        };
+
+        toml::from_str(&contents).with_context(|| format!("error parsing {}", path.display()))
+    }
+
+    /// Applies this configuration to any options that weren't given on the command line.
+    pub fn apply(self, opt: &mut Opt, matches: &ArgMatches) -> anyhow::Result<()> {
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+
+        set(
+            &mut opt.kinds,
+            unset("kinds"),
+            "kinds",
+            self.kinds.map(Terms::join),
+        )?;
+        set(
+            &mut opt.states,
+            unset("states"),
+            "states",
+            self.states.map(Terms::join),
+        )?;
+        if let Some(full_state) = self.full_state.filter(|_| unset("full_state")) {
+            opt.full_state = Some(parse("full-state", &full_state)?);
+        }
+        if let Some(listen) = self.listen.filter(|_| unset("listen")) {
+            opt.listen = listen;
+        }
+        set(&mut opt.backend, unset("backend"), "backend", self.backend)?;
+        set(&mut opt.bus, unset("bus"), "bus", self.bus)?;
+        set(&mut opt.refresh, unset("refresh"), "refresh", self.refresh)?;
+        set(&mut opt.protocol, unset("protocol"), "output", self.output)?;
+        set(
+            &mut opt.colors,
+            unset("colors"),
+            "colors",
+            self.colors.map(Terms::join),
+        )?;
+        if let Some(heartbeat) = self.heartbeat.filter(|_| unset("heartbeat")) {
+            opt.heartbeat = Some(parse("heartbeat", &heartbeat)?);
+        }
+        if let Some(cycle_signal) = self.cycle_signal.filter(|_| unset("cycle_signal")) {
+            opt.cycle_signal = Some(cycle_signal);
+        }
+        set(
+            &mut opt.aggregate,
+            unset("aggregate"),
+            "aggregate",
+            self.aggregate,
+        )?;
+        set(
+            &mut opt.separator,
+            unset("separator"),
+            "separator",
+            self.separator,
+        )?;
+        set(&mut opt.format, unset("format"), "format", self.format)?;
+        set(
+            &mut opt.tooltip_format,
+            unset("tooltip_format"),
+            "tooltip-format",
+            self.tooltip_format,
+        )?;
+        set(
+            &mut opt.on_error,
+            unset("on_error"),
+            "on-error",
+            self.on_error,
+        )?;
+        set(
+            &mut opt.error_text,
+            unset("error_text"),
+            "error-text",
+            self.error_text,
+        )?;
+
+        if let Some(notify) = self.notify.filter(|_| unset("notify")) {
+            opt.notify = notify;
+        }
+        if let Some(notify_charged) = self.notify_charged.filter(|_| unset("notify_charged")) {
+            opt.notify_charged = notify_charged;
+        }
+
+        for (option, id, value) in [
+            (&mut opt.on_threshold, "on_threshold", self.on_threshold),
+            (&mut opt.on_connect, "on_connect", self.on_connect),
+            (&mut opt.on_disconnect, "on_disconnect", self.on_disconnect),
+            (&mut opt.on_charged, "on_charged", self.on_charged),
+        ] {
+            if let Some(command) = value.filter(|_| unset(id)) {
+                *option = Some(command);
+            }
+        }
+        set(
+            &mut opt.hook_timeout,
+            unset("hook_timeout"),
+            "hook-timeout",
+            self.hook_timeout,
+        )?;
+        set(
+            &mut opt.hook_interval,
+            unset("hook_interval"),
+            "hook-interval",
+            self.hook_interval,
+        )?;
+
+        if let Some(no_history) = self.no_history.filter(|_| unset("no_history")) {
+            opt.no_history = no_history;
+        }
+        set(
+            &mut opt.sparkline_window,
+            unset("sparkline_window"),
+            "sparkline-window",
+            self.sparkline_window,
+        )?;
+
+        if let Some(matches) = self.matches.filter(|_| unset("matches")) {
+            opt.matches = parse_all("match", &matches)?;
+        }
+        if let Some(exclude) = self.exclude.filter(|_| unset("exclude")) {
+            opt.exclude = parse_all("exclude", &exclude)?;
+        }
+        if let Some(aliases) = self.aliases.filter(|_| unset("aliases")) {
+            opt.aliases = aliases
+                .into_iter()
+                .map(|(key, entry)| {
+                    let alias = match entry {
+                        AliasEntry::Name(name) => Alias { name, icon: None },
+                        AliasEntry::Full(AliasTable { name, icon }) => Alias { name, icon },
+                    };
+                    AliasRule::new(key, alias)
+                })
+                .collect();
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces an option with the configured value, if there is one and the option is unset.
+fn set<T>(option: &mut T, unset: bool, key: &str, value: Option<String>) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = value.filter(|_| unset) {
+        *option = parse(key, &value)?;
+    }
+
+    Ok(())
+}
+
+fn parse<T>(key: &str, value: &str) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    T::from_str(value).map_err(|e| anyhow::anyhow!("invalid value {value:?} for {key}: {e}"))
+}
+
+fn parse_all<T>(key: &str, values: &[String]) -> anyhow::Result<Vec<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    values.iter().map(|value| parse(key, value)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+    use crate::{Aggregate, Device, DeviceKind};
+
+    fn configured(config: &str, args: &[&str]) -> anyhow::Result<Opt> {
+        let matches = Opt::command()
+            .try_get_matches_from(std::iter::once("test").chain(args.iter().copied()))?;
+        let mut opt = Opt::from_arg_matches(&matches)?;
+        toml::from_str::<Config>(config)?.apply(&mut opt, &matches)?;
+        Ok(opt)
+    }
+
+    const CONFIG: &str = r#"
+        kinds = ["audio", "!speakers"]
+        states = "critical:10,low:20"
+        aggregate = "first"
+        format = "{name}"
+        match = ["vendor=Sony"]
+
+        [aliases]
+        "WH-1000XM4" = { name = "Headphones", icon = "🎧" }
+        "00:11:22:33:44:55" = "Headset"
+    "#;
+
+    #[test]
+    fn config_applies() -> anyhow::Result<()> {
+        let opt = configured(CONFIG, &[])?;
+        assert_eq!(opt.kinds.priority(DeviceKind::OtherAudio), Some(2));
+        assert_eq!(opt.kinds.priority(DeviceKind::Speakers), None);
+        assert_eq!(opt.states.matching(5.0).unwrap().1.class, "critical");
+        assert_eq!(opt.aggregate, Aggregate::First);
+        assert_eq!(opt.matches.len(), 1);
+
+        let device = Device {
+            model: "WH-1000XM4".to_string(),
+            vendor: "Sony".to_string(),
+            ..Default::default()
+        };
+        let output = opt.output(&[device]).unwrap();
+        assert_eq!(output.text, "Headphones");
+
+        Ok(())
+    }
+
+    #[test]
+    fn alias_key() -> anyhow::Result<()> {
+        let opt = configured("format = \"{name}\"\n[alias]\nHeadset = \"Work\"", &[])?;
+        let device = Device {
+            model: "Headset".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(opt.output(&[device]).unwrap().text, "Work");
+
+        assert!(configured("alias = {}\naliases = {}", &[]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn command_line_wins() -> anyhow::Result<()> {
+        let opt = configured(
+            CONFIG,
+            &[
+                "--aggregate",
+                "highest",
+                "-f",
+                "{model}",
+                "--kinds",
+                "mouse",
+            ],
+        )?;
+        assert_eq!(opt.aggregate, Aggregate::Highest);
+        assert_eq!(opt.kinds.priority(DeviceKind::Mouse), Some(0));
+        assert_eq!(opt.kinds.priority(DeviceKind::Headset), None);
+        assert_eq!(opt.states.matching(5.0).unwrap().1.class, "critical");
+
+        let device = Device {
+            model: "WH-1000XM4".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(opt.output(&[device]).unwrap().text, "WH-1000XM4");
+
+        let list = configured(CONFIG, &["list", "--kinds", "mouse"])?;
+        assert_eq!(list.kinds.priority(DeviceKind::Mouse), Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let error = |config| configured(config, &[]).unwrap_err().to_string();
+
+        assert!(error("colour = \"red\"").contains("unknown field `colour`"));
+        assert_eq!(
+            error("aggregate = \"median\""),
+            "invalid value \"median\" for aggregate: Matching variant not found"
+        );
+        assert!(error("format = \"{nope}\"").contains("for format: unknown placeholder {nope}"));
+    }
+
+    #[test]
+    fn load() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert!(Config::load(Some(&dir.path().join("missing.toml"))).is_err());
+
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "separator = \" | \"")?;
+        assert_eq!(Config::load(Some(&path))?.separator.as_deref(), Some(" | "));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{Device, Identity};
+
+/// The device shown when --aggregate is cycle.
+///
+/// The selected device is remembered by its identity, so it stays selected as devices are added,
+/// removed and reordered. If it disappears, the device that took its position is selected instead.
+#[derive(Debug, Default)]
+pub struct Selection {
+    identity: Option<Identity>,
+    index: usize,
+}
+
+impl Selection {
+    /// Returns the selected device.
+    pub fn select(&mut self, mut devices: Vec<Device>) -> Option<Device> {
+        let index = self.resolve(&devices)?;
+        Some(devices.swap_remove(index))
+    }
+
+    /// Moves the selection forwards or backwards by the given number of devices, wrapping around
+    /// at either end.
+    pub fn step(&mut self, devices: &[Device], offset: isize) {
+        let Some(index) = self.resolve(devices) else {
+            return;
+        };
+
+        let index = (index as isize + offset).rem_euclid(devices.len() as isize) as usize;
+        self.index = index;
+        self.identity = Some(devices[index].identity());
+    }
+
+    /// Finds the index of the selected device, updating the remembered index and identity.
+    fn resolve(&mut self, devices: &[Device]) -> Option<usize> {
+        if devices.is_empty() {
+            return None;
+        }
+
+        let index = self
+            .identity
+            .as_ref()
+            .and_then(|identity| {
+                devices
+                    .iter()
+                    .position(|device| device.identity() == *identity)
+            })
+            .unwrap_or(self.index.min(devices.len() - 1));
+
+        self.index = index;
+        self.identity = Some(devices[index].identity());
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices(models: &[&str]) -> Vec<Device> {
+        models
+            .iter()
+            .map(|model| Device {
+                model: model.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn selected(selection: &mut Selection, models: &[&str]) -> Option<String> {
+        selection.select(devices(models)).map(|device| device.model)
+    }
+
+    #[test]
+    fn step() {
+        let mut selection = Selection::default();
+        assert_eq!(selected(&mut selection, &[]), None);
+        assert_eq!(
+            selected(&mut selection, &["a", "b", "c"]).as_deref(),
+            Some("a")
+        );
+
+        selection.step(&devices(&["a", "b", "c"]), 1);
+        assert_eq!(
+            selected(&mut selection, &["a", "b", "c"]).as_deref(),
+            Some("b")
+        );
+        selection.step(&devices(&["a", "b", "c"]), 2);
+        assert_eq!(
+            selected(&mut selection, &["a", "b", "c"]).as_deref(),
+            Some("a")
+        );
+        selection.step(&devices(&["a", "b", "c"]), -1);
+        assert_eq!(
+            selected(&mut selection, &["a", "b", "c"]).as_deref(),
+            Some("c")
+        );
+    }
+
+    #[test]
+    fn follows_device() {
+        let mut selection = Selection::default();
+        selection.step(&devices(&["a", "b", "c"]), 1);
+
+        // The selection follows the device when others are added or reordered.
+        assert_eq!(
+            selected(&mut selection, &["z", "c", "b"]).as_deref(),
+            Some("b")
+        );
+
+        // If it disappears, the device in its position is selected instead.
+        assert_eq!(selected(&mut selection, &["z", "c"]).as_deref(), Some("c"));
+        selection.step(&devices(&["z", "c"]), 1);
+        assert_eq!(selected(&mut selection, &["z"]).as_deref(), Some("z"));
+    }
+}
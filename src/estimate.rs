@@ -0,0 +1,291 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{Device, DeviceState, Identity};
+
+/// Samples older than this are forgotten.
+const WINDOW: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// How quickly older samples lose influence over the estimated rate.
+const HALF_LIFE: Duration = Duration::from_secs(20 * 60);
+
+/// The samples must cover at least this long before anything is estimated, since Bluetooth devices
+/// often only report their percentage in steps of 5 or 10.
+const MIN_SPAN: Duration = Duration::from_secs(5 * 60);
+
+/// An unchanged percentage is only sampled this often, so that a refresh every few seconds
+/// doesn't crowd older samples out.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most samples kept for each device.
+const MAX_SAMPLES: usize = 512;
+
+/// Rates slower than this, in percent per second, are treated as the percentage not changing at
+/// all. This is 1% a day.
+const MIN_RATE: f64 = 1.0 / (24.0 * 60.0 * 60.0);
+
+/// Estimates how long devices will take to empty or fill, from how quickly their percentage has
+/// been changing.
+#[derive(Debug, Default)]
+pub struct Estimator {
+    samples: HashMap<Identity, Samples>,
+}
+
+#[derive(Debug)]
+struct Samples {
+    charging: bool,
+    percentages: VecDeque<(Instant, f64)>,
+}
+
+impl Estimator {
+    /// Records the current percentage of each device.
+    pub fn record(&mut self, devices: &[Device]) {
+        self.record_at(Instant::now(), devices);
+    }
+
+    /// Returns the devices with their estimates filled in.
+    pub fn estimated(&self, devices: Vec<Device>) -> Vec<Device> {
+        let now = Instant::now();
+        devices
+            .into_iter()
+            .map(|device| Device {
+                estimate: self.estimate_at(now, &device),
+                ..device
+            })
+            .collect()
+    }
+
+    fn record_at(&mut self, now: Instant, devices: &[Device]) {
+        for device in devices {
+            let charging = device.state == DeviceState::Charging;
+            let samples = self
+                .samples
+                .entry(device.identity())
+                .or_insert_with(|| Samples {
+                    charging,
+                    percentages: VecDeque::new(),
+                });
+
+            // A rate measured while discharging says nothing about charging, and vice versa.
+            if samples.charging != charging {
+                samples.charging = charging;
+                samples.percentages.clear();
+            }
+
+            let due = match samples.percentages.back() {
+                Some(&(time, percentage)) => {
+                    percentage != device.percentage || now.duration_since(time) >= SAMPLE_INTERVAL
+                }
+                None => true,
+            };
+            if due {
+                samples.percentages.push_back((now, device.percentage));
+                if samples.percentages.len() > MAX_SAMPLES {
+                    samples.percentages.pop_front();
+                }
+            }
+        }
+
+        for samples in self.samples.values_mut() {
+            while let Some(&(time, _)) = samples.percentages.front() {
+                if now.duration_since(time) <= WINDOW {
+                    break;
+                }
+                samples.percentages.pop_front();
+            }
+        }
+        self.samples
+            .retain(|_, samples| !samples.percentages.is_empty());
+    }
+
+    /// Returns the time until the device is empty or, while it's charging, full, rounded to the
+    /// minute.
+    fn estimate_at(&self, now: Instant, device: &Device) -> Option<Duration> {
+        let samples = self.samples.get(&device.identity())?;
+        let (first, _) = *samples.percentages.front()?;
+        let (last, _) = *samples.percentages.back()?;
+        if last.duration_since(first) < MIN_SPAN {
+            return None;
+        }
+        // Rounding errors in the regression would otherwise turn an unchanged percentage into a
+        // tiny, but not quite zero, rate.
+        let mut percentages = samples
+            .percentages
+            .iter()
+            .map(|&(_, percentage)| percentage);
+        let first_percentage = percentages.next()?;
+        if percentages.all(|percentage| percentage == first_percentage) {
+            return None;
+        }
+
+        // Weighted least squares, where each sample's weight halves every HALF_LIFE, and times
+        // are in seconds before now.
+        let points: Vec<_> = samples
+            .percentages
+            .iter()
+            .map(|&(time, percentage)| {
+                let age = now.duration_since(time).as_secs_f64();
+                let weight = 0.5f64.powf(age / HALF_LIFE.as_secs_f64());
+                (-age, percentage, weight)
+            })
+            .collect();
+        let total: f64 = points.iter().map(|(_, _, w)| w).sum();
+        let mean_x = points.iter().map(|(x, _, w)| x * w).sum::<f64>() / total;
+        let mean_y = points.iter().map(|(_, y, w)| y * w).sum::<f64>() / total;
+        let covariance: f64 = points
+            .iter()
+            .map(|(x, y, w)| w * (x - mean_x) * (y - mean_y))
+            .sum();
+        let variance: f64 = points
+            .iter()
+            .map(|(x, _, w)| w * (x - mean_x).powi(2))
+            .sum();
+        if variance == 0.0 {
+            return None;
+        }
+        // Percent per second.
+        let rate = covariance / variance;
+        if rate.abs() < MIN_RATE {
+            return None;
+        }
+
+        let remaining = if samples.charging {
+            (100.0 - device.percentage) / rate
+        } else {
+            device.percentage / -rate
+        };
+        if !remaining.is_finite() || remaining < 0.0 {
+            return None;
+        }
+
+        let minutes = (remaining / 60.0).round();
+        Duration::try_from_secs_f64(minutes * 60.0).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(percentage: f64, state: DeviceState) -> Device {
+        Device {
+            model: "headset".to_string(),
+            percentage,
+            state,
+            ..Default::default()
+        }
+    }
+
+    fn minutes(minutes: u64) -> Duration {
+        Duration::from_secs(minutes * 60)
+    }
+
+    #[test]
+    fn discharging() {
+        let start = Instant::now();
+        let mut estimator = Estimator::default();
+
+        // 1% every 2 minutes, so 50% will last 100 minutes.
+        for minute in 0..=10 {
+            let percentage = 55.0 - minute as f64 / 2.0;
+            estimator.record_at(
+                start + minutes(minute),
+                &[device(percentage, DeviceState::Discharging)],
+            );
+        }
+        assert_eq!(
+            estimator.estimate_at(start + minutes(10), &device(50.0, DeviceState::Discharging)),
+            Some(minutes(100))
+        );
+    }
+
+    #[test]
+    fn charging() {
+        let start = Instant::now();
+        let mut estimator = Estimator::default();
+
+        // Samples from before charging started are forgotten.
+        estimator.record_at(start, &[device(90.0, DeviceState::Discharging)]);
+        for minute in 1..=6 {
+            let percentage = 50.0 + minute as f64;
+            estimator.record_at(
+                start + minutes(minute),
+                &[device(percentage, DeviceState::Charging)],
+            );
+        }
+        assert_eq!(
+            estimator.estimate_at(start + minutes(6), &device(56.0, DeviceState::Charging)),
+            Some(minutes(44))
+        );
+    }
+
+    #[test]
+    fn not_enough_samples() {
+        let start = Instant::now();
+        let mut estimator = Estimator::default();
+        let headset = device(50.0, DeviceState::Discharging);
+        assert_eq!(estimator.estimate_at(start, &headset), None);
+
+        // Not long enough to tell.
+        estimator.record_at(start, &[device(51.0, DeviceState::Discharging)]);
+        estimator.record_at(start + minutes(1), std::slice::from_ref(&headset));
+        assert_eq!(estimator.estimate_at(start + minutes(1), &headset), None);
+
+        // Long enough, but unchanged.
+        let mut estimator = Estimator::default();
+        for minute in 0..=10 {
+            estimator.record_at(start + minutes(minute), std::slice::from_ref(&headset));
+        }
+        assert_eq!(estimator.estimate_at(start + minutes(10), &headset), None);
+    }
+
+    #[test]
+    fn unchanged() {
+        // Rounding errors in the regression depend on exactly when the samples were taken, so try
+        // a range of slightly irregular sample times.
+        let start = Instant::now();
+        for percentage in [0.1, 33.3, 50.0, 73.0, 99.9] {
+            for jitter in 0..40 {
+                let mut estimator = Estimator::default();
+                let headset = device(percentage, DeviceState::Discharging);
+                for i in 0..=20 {
+                    let offset = Duration::from_micros(i * jitter * 7919 % 999_983);
+                    estimator
+                        .record_at(start + minutes(i) + offset, std::slice::from_ref(&headset));
+                }
+                let now = start + minutes(20) + Duration::from_micros(jitter * 123_457);
+                assert_eq!(
+                    estimator.estimate_at(now, &headset),
+                    None,
+                    "{percentage}% with jitter {jitter}"
+                );
+            }
+        }
+
+        // Nor does a rate too slow to mean anything.
+        let mut estimator = Estimator::default();
+        estimator.record_at(start, &[device(50.0, DeviceState::Discharging)]);
+        estimator.record_at(
+            start + minutes(10),
+            &[device(49.999, DeviceState::Discharging)],
+        );
+        assert_eq!(
+            estimator.estimate_at(
+                start + minutes(10),
+                &device(49.999, DeviceState::Discharging)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn forgets_old_samples() {
+        let start = Instant::now();
+        let mut estimator = Estimator::default();
+        estimator.record_at(start, &[device(50.0, DeviceState::Discharging)]);
+        estimator.record_at(start + WINDOW + minutes(1), &[]);
+        assert!(estimator.samples.is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+use std::{fmt, str::FromStr};
+
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use strum::{EnumString, VariantNames};
+
+use crate::{Device, DeviceKindSet};
+
+/// Decides which devices are included in output, and in what order.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    kinds: DeviceKindSet,
+    include: Vec<Predicate>,
+    exclude: Vec<Predicate>,
+}
+
+impl Filter {
+    pub fn new(kinds: DeviceKindSet, include: Vec<Predicate>, exclude: Vec<Predicate>) -> Self {
+        Self {
+            kinds,
+            include,
+            exclude,
+        }
+    }
+
+    /// Returns the priority of the given device, where lower is higher priority, or `None` if it
+    /// doesn't match.
+    ///
+    /// A device matches if its kind is in the kind set, it matches any of the include predicates
+    /// (if there are any), and it matches none of the exclude predicates.
+    pub fn priority(&self, device: &Device) -> Option<usize> {
+        let priority = self.kinds.priority(device.kind)?;
+
+        if !self.include.is_empty() && !self.include.iter().any(|p| p.matches(device)) {
+            return None;
+        }
+        if self.exclude.iter().any(|p| p.matches(device)) {
+            return None;
+        }
+
+        Some(priority)
+    }
+}
+
+impl From<DeviceKindSet> for Filter {
+    fn from(kinds: DeviceKindSet) -> Self {
+        Self::new(kinds, Vec::new(), Vec::new())
+    }
+}
+
+/// A device property that predicates can test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, VariantNames)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Field {
+    Model,
+    Vendor,
+    Serial,
+    NativePath,
+    /// The Bluetooth address, taken from the native path or the serial.
+    Address,
+}
+
+impl Field {
+    fn value(self, device: &Device) -> Option<String> {
+        match self {
+            Self::Model => Some(device.model.clone()),
+            Self::Vendor => Some(device.vendor.clone()),
+            Self::Serial => Some(device.serial.clone()),
+            Self::NativePath => Some(device.native_path.clone()),
+            Self::Address => address(device),
+        }
+    }
+}
+
+/// Returns the Bluetooth address of a device, if it has one.
+///
+/// UPower uses the BlueZ object path (such as /org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF) as the native
+/// path of Bluetooth devices, and the address as their serial.
+pub fn address(device: &Device) -> Option<String> {
+    let from_path = device
+        .native_path
+        .rsplit('/')
+        .next()
+        .and_then(|segment| segment.strip_prefix("dev_"))
+        .map(|address| address.replace('_', ":"));
+
+    from_path
+        .into_iter()
+        .chain(Some(device.serial.clone()))
+        .find(|candidate| is_address(candidate))
+        .map(|address| address.to_uppercase())
+}
+
+fn is_address(s: &str) -> bool {
+    let octets: Vec<_> = s.split(':').collect();
+    octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// A test against a single device field, either a case insensitive glob (FIELD=GLOB) or a regular
+/// expression (FIELD~REGEX).
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    field: Field,
+    pattern: Pattern,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Predicate {
+    pub fn matches(&self, device: &Device) -> bool {
+        let Some(value) = self.field.value(device) else {
+            return false;
+        };
+
+        match &self.pattern {
+            Pattern::Glob(glob) => glob.is_match(&value),
+            Pattern::Regex(regex) => regex.is_match(&value),
+        }
+    }
+
+    pub fn long_help(summary: &str) -> String {
+        format!(
+            "{summary} May be given multiple times.\n\nEach predicate is either FIELD=GLOB, \
+             matching a case insensitive glob against the whole field, or FIELD~REGEX, \
+             searching the field for a regular expression. Possible fields: {}. For example, \
+             \"model=WH-1000XM*\" or \"address~^00:1B:66\".",
+            Field::VARIANTS.join(", ")
+        )
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = PredicateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let index = s
+            .find(['=', '~'])
+            .ok_or_else(|| PredicateError::MissingOperator(s.to_string()))?;
+        let (field, rest) = s.split_at(index);
+
+        let field = field.trim();
+        let field =
+            Field::from_str(field).map_err(|_| PredicateError::UnknownField(field.to_string()))?;
+
+        let pattern = match rest.split_at(1) {
+            ("=", glob) => Pattern::Glob(
+                GlobBuilder::new(glob)
+                    .case_insensitive(true)
+                    .literal_separator(false)
+                    .build()
+                    .map_err(|e| PredicateError::InvalidGlob(e.to_string()))?
+                    .compile_matcher(),
+            ),
+            (_, regex) => Pattern::Regex(
+                Regex::new(regex).map_err(|e| PredicateError::InvalidRegex(e.to_string()))?,
+            ),
+        };
+
+        Ok(Self { field, pattern })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PredicateError {
+    MissingOperator(String),
+    UnknownField(String),
+    InvalidGlob(String),
+    InvalidRegex(String),
+}
+
+impl fmt::Display for PredicateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingOperator(term) => {
+                write!(
+                    f,
+                    "predicate {term:?} must be in the form FIELD=GLOB or FIELD~REGEX"
+                )
+            }
+            Self::UnknownField(field) => write!(
+                f,
+                "unknown field {field:?}, expected one of: {}",
+                Field::VARIANTS.join(", ")
+            ),
+            Self::InvalidGlob(e) => write!(f, "invalid glob: {e}"),
+            Self::InvalidRegex(e) => write!(f, "invalid regular expression: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PredicateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceKind;
+
+    fn device(model: &str, serial: &str, native_path: &str) -> Device {
+        Device {
+            kind: DeviceKind::Headset,
+            model: model.to_string(),
+            vendor: "Sony".to_string(),
+            serial: serial.to_string(),
+            native_path: native_path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn predicate(s: &str) -> Predicate {
+        Predicate::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn predicates() {
+        let headset = device("WH-1000XM4", "", "/org/bluez/hci0/dev_aa_bb_cc_dd_ee_ff");
+
+        assert!(predicate("model=wh-1000xm*").matches(&headset));
+        assert!(!predicate("model=1000XM*").matches(&headset));
+        assert!(predicate("model~1000XM").matches(&headset));
+        assert!(!predicate("model~^1000XM").matches(&headset));
+        assert!(predicate("vendor=sony").matches(&headset));
+        assert!(predicate("native-path=*/hci0/*").matches(&headset));
+        assert!(predicate("address=AA:BB:CC:DD:EE:FF").matches(&headset));
+        assert!(!predicate("serial=?*").matches(&headset));
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(
+            address(&device("", "", "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF")).as_deref(),
+            Some("AA:BB:CC:DD:EE:FF")
+        );
+        assert_eq!(
+            address(&device("", "aa:bb:cc:dd:ee:ff", "")).as_deref(),
+            Some("AA:BB:CC:DD:EE:FF")
+        );
+        assert_eq!(
+            address(&device("", "12345", "/sys/class/power_supply/BAT0")),
+            None
+        );
+        assert!(!predicate("address=*").matches(&device("", "", "")));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Predicate::from_str("model").unwrap_err(),
+            PredicateError::MissingOperator("model".to_string())
+        );
+        assert_eq!(
+            Predicate::from_str("colour=red").unwrap_err(),
+            PredicateError::UnknownField("colour".to_string())
+        );
+        assert!(matches!(
+            Predicate::from_str("model=[").unwrap_err(),
+            PredicateError::InvalidGlob(_)
+        ));
+        assert!(matches!(
+            Predicate::from_str("model~(").unwrap_err(),
+            PredicateError::InvalidRegex(_)
+        ));
+    }
+
+    #[test]
+    fn filter() {
+        let kinds = DeviceKindSet::from_str("headset").unwrap();
+        let a = device("Headset A", "AA:BB:CC:DD:EE:01", "");
+        let b = device("Headset B", "AA:BB:CC:DD:EE:02", "");
+        let mouse = Device {
+            kind: DeviceKind::Mouse,
+            ..a.clone()
+        };
+
+        let filter = Filter::from(kinds.clone());
+        assert_eq!(filter.priority(&a), Some(0));
+        assert_eq!(filter.priority(&mouse), None);
+
+        let filter = Filter::new(
+            kinds.clone(),
+            vec![predicate("model=headset *")],
+            vec![predicate("serial~02$")],
+        );
+        assert_eq!(filter.priority(&a), Some(0));
+        assert_eq!(filter.priority(&b), None);
+
+        let filter = Filter::new(kinds, vec![predicate("model=*b")], Vec::new());
+        assert_eq!(filter.priority(&a), None);
+        assert_eq!(filter.priority(&b), Some(0));
+    }
+}
@@ -0,0 +1,682 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{alias, filter, Device, DeviceKind, DeviceState, Opt};
+
+/// An unchanged percentage is only recorded this often.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The most samples kept for each device. The file is compacted once any device has a quarter as
+/// many again.
+const MAX_SAMPLES: usize = 4096;
+
+/// Samples older than this are dropped when the file is compacted.
+const MAX_AGE: Duration = Duration::from_secs(180 * 24 * 60 * 60);
+
+/// Consecutive samples further apart than this aren't counted towards runtime, since the device
+/// was probably switched off or out of range in between.
+const MAX_GAP: Duration = Duration::from_secs(30 * 60);
+
+/// The runtime per full charge is only estimated once at least this much has been discharged, over
+/// at least MIN_RUNTIME.
+const MIN_DISCHARGE: f64 = 10.0;
+const MIN_RUNTIME: Duration = Duration::from_secs(30 * 60);
+
+/// Sparkline bars, from empty to full.
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The number of bars in a sparkline, each covering an equal part of the window.
+const SPARKLINE_WIDTH: u64 = 12;
+
+/// Returns the default history file, in the XDG state directory.
+pub fn default_path() -> Option<PathBuf> {
+    Some(
+        dirs::state_dir()?
+            .join(env!("CARGO_PKG_NAME"))
+            .join("history.jsonl"),
+    )
+}
+
+/// A line of the history file. Lines are only ever appended, apart from when the file is
+/// compacted, so that several instances can record to the same file. Compacting locks the file
+/// and re-reads it first, so that lines other instances appended aren't lost.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Line {
+    key: String,
+    model: String,
+    kind: DeviceKind,
+    /// The rest of what the filter options can match on.
+    #[serde(default)]
+    vendor: String,
+    #[serde(default)]
+    serial: String,
+    #[serde(default)]
+    native_path: String,
+    #[serde(flatten)]
+    sample: Sample,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub percentage: f64,
+    pub state: DeviceState,
+}
+
+#[derive(Debug, Default)]
+struct DeviceHistory {
+    model: String,
+    kind: DeviceKind,
+    vendor: String,
+    serial: String,
+    native_path: String,
+    samples: VecDeque<Sample>,
+}
+
+impl DeviceHistory {
+    /// Enough of the device to match it against the filter options and aliases.
+    fn device(&self) -> Device {
+        Device {
+            kind: self.kind,
+            model: self.model.clone(),
+            vendor: self.vendor.clone(),
+            serial: self.serial.clone(),
+            native_path: self.native_path.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Timestamped samples of each device's percentage and state, persisted across runs.
+///
+/// Devices are keyed by their Bluetooth address or serial, so devices without either aren't
+/// recorded.
+#[derive(Debug, Default)]
+pub struct History {
+    /// Where samples are recorded, or `None` if they aren't.
+    path: Option<PathBuf>,
+    devices: BTreeMap<String, DeviceHistory>,
+}
+
+impl History {
+    /// Reads the history file at the default path. A missing file is an empty history.
+    pub fn load() -> anyhow::Result<Self> {
+        let Some(path) = default_path() else {
+            return Ok(Self::default());
+        };
+
+        let mut history = Self::default();
+        history
+            .read(&path)
+            .with_context(|| format!("error reading {}", path.display()))?;
+        Ok(Self {
+            path: Some(path),
+            ..history
+        })
+    }
+
+    /// Records the current percentage and state of each device, if they've changed or haven't
+    /// been recorded for a while. Unless `persist` is set, they're only recorded in memory.
+    pub fn record(&mut self, devices: &[Device], persist: bool) -> anyhow::Result<()> {
+        let now = unix_time();
+        let lines = self.record_at(now, devices);
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let compact = self
+            .devices
+            .values()
+            .any(|device| device.samples.len() > MAX_SAMPLES + MAX_SAMPLES / 4);
+
+        let Some(path) = self.path.clone().filter(|_| persist) else {
+            if compact {
+                self.trim(now);
+            }
+            return Ok(());
+        };
+        if compact {
+            self.compact(&path, now, &lines)
+        } else {
+            let _lock = lock(&path, false)?;
+            append(&path, &lines)
+        }
+        .with_context(|| format!("error writing {}", path.display()))
+    }
+
+    /// Returns the devices with sparklines of their percentage over the given window filled in.
+    pub fn with_sparklines(&self, devices: Vec<Device>, window: Duration) -> Vec<Device> {
+        let now = unix_time();
+        devices
+            .into_iter()
+            .map(|device| Device {
+                sparkline: key(&device)
+                    .and_then(|key| self.devices.get(&key))
+                    .and_then(|history| sparkline(&history.samples, now, window)),
+                ..device
+            })
+            .collect()
+    }
+
+    /// Returns the lines to append for any devices that are due a sample.
+    fn record_at(&mut self, now: u64, devices: &[Device]) -> Vec<Line> {
+        let mut lines = Vec::new();
+        for device in devices {
+            let Some(key) = key(device) else {
+                continue;
+            };
+
+            let due = match self
+                .devices
+                .get(&key)
+                .and_then(|history| history.samples.back())
+            {
+                Some(last) => {
+                    last.percentage != device.percentage
+                        || last.state != device.state
+                        || now.saturating_sub(last.time) >= SAMPLE_INTERVAL.as_secs()
+                }
+                None => true,
+            };
+            if !due {
+                continue;
+            }
+
+            let line = Line {
+                key,
+                model: device.model.clone(),
+                kind: device.kind,
+                vendor: device.vendor.clone(),
+                serial: device.serial.clone(),
+                native_path: device.native_path.clone(),
+                sample: Sample {
+                    time: now,
+                    percentage: device.percentage,
+                    state: device.state,
+                },
+            };
+            self.insert(line.clone());
+            lines.push(line);
+        }
+
+        lines
+    }
+
+    fn insert(&mut self, line: Line) {
+        let history = self.devices.entry(line.key).or_default();
+        history.model = line.model;
+        history.kind = line.kind;
+        history.vendor = line.vendor;
+        history.serial = line.serial;
+        history.native_path = line.native_path;
+        history.samples.push_back(line.sample);
+    }
+
+    /// Drops old samples, and any samples beyond MAX_SAMPLES for each device.
+    fn trim(&mut self, now: u64) {
+        let cutoff = now.saturating_sub(MAX_AGE.as_secs());
+        for history in self.devices.values_mut() {
+            history.samples.retain(|sample| sample.time >= cutoff);
+            let excess = history.samples.len().saturating_sub(MAX_SAMPLES);
+            history.samples.drain(..excess);
+        }
+        self.devices
+            .retain(|_, history| !history.samples.is_empty());
+    }
+
+    /// Adds the samples from a history file. A missing file is empty.
+    fn read(&mut self, path: &Path) -> anyhow::Result<()> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        for line in BufReader::new(file).lines() {
+            // A line can be cut short if we're killed while writing it, which shouldn't lose
+            // everything else.
+            if let Ok(line) = serde_json::from_str(&line?) {
+                self.insert(line);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends the given lines, then replaces the file with its trimmed contents, which include
+    /// anything other instances have recorded since it was loaded.
+    fn compact(&mut self, path: &Path, now: u64, lines: &[Line]) -> anyhow::Result<()> {
+        let _lock = lock(path, true)?;
+        append(path, lines)?;
+        let mut history = Self::default();
+        history.read(path)?;
+        self.devices = history.devices;
+        self.trim(now);
+        self.rewrite(path)
+    }
+
+    /// Replaces the file with the samples in memory.
+    fn rewrite(&self, path: &Path) -> anyhow::Result<()> {
+        let lines: Vec<_> = self
+            .devices
+            .iter()
+            .flat_map(|(key, history)| {
+                history.samples.iter().map(|&sample| Line {
+                    key: key.clone(),
+                    model: history.model.clone(),
+                    kind: history.kind,
+                    vendor: history.vendor.clone(),
+                    serial: history.serial.clone(),
+                    native_path: history.native_path.clone(),
+                    sample,
+                })
+            })
+            .collect();
+
+        // Write to a temporary file first, so the history isn't lost if we're killed part way.
+        let temporary = path.with_extension("jsonl.tmp");
+        std::fs::remove_file(&temporary).or_else(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })?;
+        append(&temporary, &lines)?;
+        std::fs::rename(&temporary, path)?;
+
+        Ok(())
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Locks a history file against other instances until the returned file is dropped. Appending only
+/// needs a shared lock, while compacting needs an exclusive one. The lock is on a separate file,
+/// since compacting replaces the history file.
+fn lock(path: &Path, exclusive: bool) -> anyhow::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.with_extension("jsonl.lock"))?;
+    if exclusive {
+        file.lock()?;
+    } else {
+        file.lock_shared()?;
+    }
+
+    Ok(file)
+}
+
+/// Appends lines to a file, creating it and its directory if necessary.
+fn append(path: &Path, lines: &[Line]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut contents = String::new();
+    for line in lines {
+        contents.push_str(&serde_json::to_string(line)?);
+        contents.push('\n');
+    }
+    // A single write, so that lines from different instances don't interleave.
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(contents.as_bytes())?;
+
+    Ok(())
+}
+
+/// The key a device's history is recorded under.
+fn key(device: &Device) -> Option<String> {
+    filter::address(device).or_else(|| Some(device.serial.clone()).filter(|s| !s.is_empty()))
+}
+
+/// Returns the average time the device ran for per full charge, extrapolated from the time spent
+/// discharging and how much was discharged in that time.
+fn runtime(samples: &VecDeque<Sample>) -> Option<Duration> {
+    let mut seconds = 0;
+    let mut discharged = 0.0;
+    for (a, b) in samples.iter().zip(samples.iter().skip(1)) {
+        let gap = b.time.saturating_sub(a.time);
+        if gap <= MAX_GAP.as_secs() && !charging(a, b) {
+            seconds += gap;
+            discharged += a.percentage - b.percentage;
+        }
+    }
+
+    if discharged < MIN_DISCHARGE || seconds < MIN_RUNTIME.as_secs() {
+        return None;
+    }
+    let minutes = (seconds as f64 * 100.0 / discharged / 60.0).round();
+    Some(Duration::from_secs(minutes as u64 * 60))
+}
+
+/// Returns how many times the device started charging.
+fn charge_cycles(samples: &VecDeque<Sample>) -> usize {
+    let mut cycles = 0;
+    let mut was_charging = false;
+    for (a, b) in samples.iter().zip(samples.iter().skip(1)) {
+        let charging = charging(a, b);
+        if charging && !was_charging {
+            cycles += 1;
+        }
+        was_charging = charging;
+    }
+
+    cycles
+}
+
+/// Whether the device charged between two samples. Not every source reports the charging state,
+/// so an increased percentage counts too.
+fn charging(a: &Sample, b: &Sample) -> bool {
+    a.state == DeviceState::Charging
+        || b.state == DeviceState::Charging
+        || b.percentage > a.percentage
+}
+
+/// Draws the mean percentage over each part of the window as a bar, followed by the minimum and
+/// maximum percentage. Parts without any samples are blank, and those before the first sample are
+/// left out.
+fn sparkline(samples: &VecDeque<Sample>, now: u64, window: Duration) -> Option<String> {
+    let window = window.as_secs().max(1);
+    let start = now.saturating_sub(window);
+    let recent: Vec<_> = samples
+        .iter()
+        .filter(|sample| (start..=now).contains(&sample.time))
+        .collect();
+
+    let mut buckets = vec![(0.0, 0); SPARKLINE_WIDTH as usize];
+    for sample in recent.iter() {
+        let index = ((sample.time - start) * SPARKLINE_WIDTH / window).min(SPARKLINE_WIDTH - 1);
+        let (total, count) = &mut buckets[index as usize];
+        *total += sample.percentage;
+        *count += 1;
+    }
+    let bars: String = buckets
+        .into_iter()
+        .skip_while(|&(_, count)| count == 0)
+        .map(|(total, count)| match count {
+            0 => ' ',
+            _ => {
+                let level = (total / count as f64 / 100.0 * (BARS.len() - 1) as f64).round();
+                BARS[(level as usize).min(BARS.len() - 1)]
+            }
+        })
+        .collect();
+
+    let min = recent
+        .iter()
+        .map(|sample| sample.percentage)
+        .reduce(f64::min)?;
+    let max = recent
+        .iter()
+        .map(|sample| sample.percentage)
+        .reduce(f64::max)?;
+    Some(if min == max {
+        format!("{bars} {min}%")
+    } else {
+        format!("{bars} {min}–{max}%")
+    })
+}
+
+/// A device's history, as output by the history subcommand.
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    key: &'a str,
+    name: String,
+    model: &'a str,
+    kind: DeviceKind,
+    /// Average runtime per full charge, in seconds.
+    runtime: Option<u64>,
+    charge_cycles: usize,
+    samples: Vec<Sample>,
+}
+
+/// Prints the recorded history of each device matching the filter options, with the most recent
+/// samples.
+pub fn show(opt: &Opt, json: bool, samples: usize) -> anyhow::Result<()> {
+    let history = History::load()?;
+    let filter = opt.filter();
+
+    let mut entries = Vec::new();
+    for (key, device_history) in history.devices.iter() {
+        let device = device_history.device();
+        if filter.priority(&device).is_none() {
+            continue;
+        }
+        let device = Device {
+            alias: alias::resolve(&opt.aliases, &device),
+            ..device
+        };
+
+        let skip = device_history.samples.len().saturating_sub(samples);
+        entries.push(Entry {
+            key,
+            name: device.name().to_string(),
+            model: &device_history.model,
+            kind: device_history.kind,
+            runtime: runtime(&device_history.samples).map(|runtime| runtime.as_secs()),
+            charge_cycles: charge_cycles(&device_history.samples),
+            samples: device_history.samples.iter().skip(skip).copied().collect(),
+        });
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        print!("{}", text(&entries));
+    }
+
+    Ok(())
+}
+
+fn text(entries: &[Entry]) -> String {
+    let mut output = String::new();
+    for entry in entries {
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(&format!("{} ({})\n", entry.name, entry.key));
+        let runtime = match entry.runtime {
+            Some(seconds) => humantime::format_duration(Duration::from_secs(seconds)).to_string(),
+            None => "unknown".to_string(),
+        };
+        output.push_str(&format!("  Average runtime per full charge: {runtime}\n"));
+        output.push_str(&format!("  Charge cycles: {}\n", entry.charge_cycles));
+        for sample in entry.samples.iter() {
+            let time = UNIX_EPOCH + Duration::from_secs(sample.time);
+            output.push_str(&format!(
+                "  {}  {:>4}  {}\n",
+                humantime::format_rfc3339_seconds(time),
+                format!("{}%", sample.percentage),
+                sample.state
+            ));
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(minutes: u64, percentage: f64, state: DeviceState) -> Sample {
+        Sample {
+            time: minutes * 60,
+            percentage,
+            state,
+        }
+    }
+
+    fn device(percentage: f64, state: DeviceState) -> Device {
+        Device {
+            model: "WH-1000XM4".to_string(),
+            serial: "00:11:22:33:44:55".to_string(),
+            percentage,
+            state,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn record() {
+        let mut history = History::default();
+        let mut record = |minutes: u64, percentage, state| {
+            history
+                .record_at(minutes * 60, &[device(percentage, state)])
+                .len()
+        };
+
+        assert_eq!(record(0, 50.0, DeviceState::Discharging), 1);
+        assert_eq!(record(1, 50.0, DeviceState::Discharging), 0);
+        assert_eq!(record(2, 40.0, DeviceState::Discharging), 1);
+        assert_eq!(record(3, 40.0, DeviceState::Charging), 1);
+        assert_eq!(record(13, 40.0, DeviceState::Charging), 1);
+
+        // Devices without a serial or address can't be told apart between runs.
+        let anonymous = Device {
+            serial: String::new(),
+            ..device(50.0, DeviceState::Discharging)
+        };
+        assert!(history.record_at(0, &[anonymous]).is_empty());
+
+        assert_eq!(history.devices["00:11:22:33:44:55"].samples.len(), 4);
+    }
+
+    #[test]
+    fn statistics() {
+        let samples = VecDeque::from([
+            // Switched off for a day, which doesn't count as runtime.
+            sample(60, 90.0, DeviceState::Discharging),
+            sample(24 * 60, 90.0, DeviceState::Discharging),
+            sample(24 * 60 + 30, 85.0, DeviceState::Discharging),
+            sample(24 * 60 + 40, 86.0, DeviceState::Unknown),
+            sample(24 * 60 + 50, 90.0, DeviceState::Charging),
+            sample(24 * 60 + 60, 80.0, DeviceState::Discharging),
+            sample(24 * 60 + 70, 75.0, DeviceState::Discharging),
+            sample(24 * 60 + 80, 80.0, DeviceState::Charging),
+        ]);
+
+        // 5% in 30 minutes and 5% in 10 minutes, so 10% in 40 minutes.
+        assert_eq!(runtime(&samples), Some(Duration::from_secs(400 * 60)));
+        assert_eq!(charge_cycles(&samples), 2);
+
+        assert_eq!(runtime(&samples.iter().take(2).copied().collect()), None);
+    }
+
+    #[test]
+    fn sparklines() {
+        let hour = 60;
+        let samples = VecDeque::from([
+            // Outside the window.
+            sample(0, 100.0, DeviceState::Discharging),
+            sample(20 * hour, 100.0, DeviceState::Discharging),
+            sample(20 * hour + 30, 90.0, DeviceState::Discharging),
+            sample(21 * hour, 70.0, DeviceState::Discharging),
+            // Nothing from 22 to 23 hours.
+            sample(23 * hour + 30, 10.0, DeviceState::Charging),
+            sample(24 * hour, 60.0, DeviceState::Charging),
+        ]);
+        let now = 24 * 60 * 60;
+        let window = Duration::from_secs(12 * 60 * 60);
+
+        assert_eq!(
+            sparkline(&samples, now, window).as_deref(),
+            Some("█▆ ▃ 10–100%")
+        );
+        assert_eq!(
+            sparkline(&samples, now, Duration::from_secs(60)).as_deref(),
+            Some("▅ 60%")
+        );
+        assert_eq!(sparkline(&VecDeque::new(), now, window), None);
+    }
+
+    #[test]
+    fn trim() {
+        let mut history = History::default();
+        let now = MAX_AGE.as_secs() + 3600;
+        history.record_at(0, &[device(50.0, DeviceState::Discharging)]);
+        for i in 0..MAX_SAMPLES as u64 + 10 {
+            history.record_at(now - 60 + i, &[device(i as f64, DeviceState::Discharging)]);
+        }
+        history.trim(now);
+
+        let samples = &history.devices["00:11:22:33:44:55"].samples;
+        assert_eq!(samples.len(), MAX_SAMPLES);
+        assert_eq!(samples.front().unwrap().percentage, 10.0);
+    }
+
+    #[test]
+    fn compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let mut first = History {
+            path: Some(path.clone()),
+            ..History::default()
+        };
+        let mut second = History {
+            path: Some(path.clone()),
+            ..History::default()
+        };
+        let other = Device {
+            serial: "66:77:88:99:AA:BB".to_string(),
+            ..device(80.0, DeviceState::Discharging)
+        };
+        first
+            .record(&[device(50.0, DeviceState::Discharging)], true)
+            .unwrap();
+        second.record(&[other], true).unwrap();
+
+        // What the second instance recorded survives the first compacting the file.
+        first.compact(&path, unix_time(), &[]).unwrap();
+        let mut history = History::default();
+        history.read(&path).unwrap();
+        for history in [&first, &history] {
+            assert_eq!(
+                history.devices.keys().collect::<Vec<_>>(),
+                ["00:11:22:33:44:55", "66:77:88:99:AA:BB"]
+            );
+        }
+    }
+
+    #[test]
+    fn text_output() {
+        let entry = Entry {
+            key: "00:11:22:33:44:55",
+            name: "Headphones".to_string(),
+            model: "WH-1000XM4",
+            kind: DeviceKind::Headphones,
+            runtime: Some(20 * 60 * 60),
+            charge_cycles: 3,
+            samples: vec![sample(0, 100.0, DeviceState::Discharging)],
+        };
+
+        assert_eq!(
+            text(&[entry]),
+            "Headphones (00:11:22:33:44:55)\n  \
+             Average runtime per full charge: 20h\n  \
+             Charge cycles: 3\n  \
+             1970-01-01T00:00:00Z  100%  discharging\n"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::{collections::HashMap, process::Stdio, time::Duration};
+
+use strum::Display;
+use tokio::{process::Command, time::Instant};
+
+use crate::{filter, transitions::Transition, Device, Identity, Opt};
+
+/// The kinds of transition that hooks can be configured for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display)]
+#[strum(serialize_all = "kebab-case")]
+enum HookEvent {
+    Threshold,
+    Connect,
+    Disconnect,
+    Charged,
+}
+
+/// Runs the configured hook commands in response to transitions.
+#[derive(Default)]
+pub struct Hooks {
+    /// When each hook last ran for each device, and threshold for --on-threshold, for rate
+    /// limiting.
+    last_run: HashMap<(HookEvent, Identity, Option<usize>), Instant>,
+}
+
+impl Hooks {
+    /// Starts the hooks for the given transitions in the background, skipping any that ran for the
+    /// same device within --hook-interval. A threshold hook is only skipped if it ran for the same
+    /// threshold, so a more severe one isn't missed. Failures are logged.
+    pub fn run(&mut self, opt: &Opt, transitions: &[Transition]) {
+        for transition in transitions {
+            let (event, device, threshold, mut env) = match transition {
+                Transition::Crossed {
+                    device,
+                    severity,
+                    class,
+                } => (
+                    HookEvent::Threshold,
+                    device,
+                    Some(*severity),
+                    vec![
+                        ("BATTERY_CLASS", class.clone()),
+                        ("BATTERY_SEVERITY", severity.to_string()),
+                    ],
+                ),
+                Transition::Connected(device) => (HookEvent::Connect, device, None, Vec::new()),
+                Transition::Disconnected(device) => {
+                    (HookEvent::Disconnect, device, None, Vec::new())
+                }
+                Transition::Charged(device) => (HookEvent::Charged, device, None, Vec::new()),
+            };
+
+            let command = match event {
+                HookEvent::Threshold => &opt.on_threshold,
+                HookEvent::Connect => &opt.on_connect,
+                HookEvent::Disconnect => &opt.on_disconnect,
+                HookEvent::Charged => &opt.on_charged,
+            };
+            let Some(command) = command else {
+                continue;
+            };
+
+            let interval: Duration = opt.hook_interval.into();
+            let key = (event, device.identity(), threshold);
+            if let Some(last_run) = self.last_run.get(&key) {
+                if last_run.elapsed() < interval {
+                    continue;
+                }
+            }
+            self.last_run.insert(key, Instant::now());
+
+            env.push(("BATTERY_EVENT", event.to_string()));
+            env.extend(environment(device));
+            tokio::spawn(run(command.clone(), env, opt.hook_timeout.into()));
+        }
+    }
+}
+
+/// The environment variables describing a device.
+fn environment(device: &Device) -> Vec<(&'static str, String)> {
+    vec![
+        ("BATTERY_NAME", device.name().to_string()),
+        ("BATTERY_MODEL", device.model.clone()),
+        ("BATTERY_VENDOR", device.vendor.clone()),
+        ("BATTERY_SERIAL", device.serial.clone()),
+        (
+            "BATTERY_ADDRESS",
+            filter::address(device).unwrap_or_default(),
+        ),
+        ("BATTERY_NATIVE_PATH", device.native_path.clone()),
+        ("BATTERY_KIND", device.kind.to_string()),
+        ("BATTERY_PERCENTAGE", device.percentage.to_string()),
+        ("BATTERY_STATE", device.state.to_string()),
+    ]
+}
+
+/// Runs a hook command to completion, killing it and anything it started if it takes longer than
+/// the timeout.
+async fn run(command: String, env: Vec<(&'static str, String)>, timeout: Duration) {
+    let result = async {
+        // The hook's output mustn't end up mixed in with ours, but its errors can go in our log. It
+        // gets its own process group, so that it can be killed along with anything it started.
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()?;
+
+        match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => {
+                let status = status?;
+                anyhow::ensure!(status.success(), "{status}");
+            }
+            Err(_) => {
+                if let Some(pid) = child.id() {
+                    // SAFETY: killpg has no memory safety requirements. The group's ID is the
+                    // shell's PID, which can't have been reused, since the shell hasn't been
+                    // reaped.
+                    unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+                }
+                child.kill().await?;
+                anyhow::bail!("timed out after {}", humantime::format_duration(timeout));
+            }
+        }
+
+        Ok(())
+    };
+
+    if let Err(e) = result.await {
+        eprintln!("hook {command:?} failed: {e:#}");
+    }
+}
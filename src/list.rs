@@ -0,0 +1,128 @@
+use serde::Serialize;
+
+use crate::{filter::Filter, source::DeviceSource};
+
+/// A single device, as output by the list subcommand.
+#[derive(Debug, PartialEq, Serialize)]
+struct Entry {
+    path: String,
+    native_path: String,
+    kind: String,
+    model: String,
+    vendor: String,
+    serial: String,
+    percentage: f64,
+    state: String,
+    matches: bool,
+}
+
+impl Entry {
+    const HEADERS: [&'static str; 9] = [
+        "PATH",
+        "NATIVE PATH",
+        "KIND",
+        "MODEL",
+        "VENDOR",
+        "SERIAL",
+        "PERCENTAGE",
+        "STATE",
+        "MATCHES",
+    ];
+
+    fn columns(&self) -> [String; 9] {
+        [
+            self.path.clone(),
+            self.native_path.clone(),
+            self.kind.clone(),
+            self.model.clone(),
+            self.vendor.clone(),
+            self.serial.clone(),
+            format!("{}%", self.percentage),
+            self.state.clone(),
+            if self.matches { "yes" } else { "no" }.to_string(),
+        ]
+    }
+}
+
+/// Prints every device the source knows about, regardless of whether it matches.
+pub async fn list(source: &impl DeviceSource, filter: &Filter, json: bool) -> anyhow::Result<()> {
+    let mut entries = Vec::new();
+    for id in source.enumerate().await? {
+        // Devices can disappear between being enumerated and being read.
+        let Some(device) = source.snapshot(&id).await? else {
+            continue;
+        };
+
+        let matches = filter.priority(&device).is_some();
+        entries.push(Entry {
+            path: id,
+            native_path: device.native_path,
+            kind: device.kind.to_string(),
+            model: device.model,
+            vendor: device.vendor,
+            serial: device.serial,
+            percentage: device.percentage,
+            state: device.state.to_string(),
+            matches,
+        });
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        print!("{}", table(&entries));
+    }
+
+    Ok(())
+}
+
+fn table(entries: &[Entry]) -> String {
+    let rows: Vec<_> = entries.iter().map(Entry::columns).collect();
+
+    let mut widths = Entry::HEADERS.map(str::len);
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+
+    let mut output = String::new();
+    for row in std::iter::once(Entry::HEADERS.map(String::from)).chain(rows) {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(column, width)| format!("{column:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_alignment() {
+        let entry = Entry {
+            path: "/org/freedesktop/UPower/devices/headset".to_string(),
+            native_path: "/org/bluez/hci0/dev_00_11_22_33_44_55".to_string(),
+            kind: "headset".to_string(),
+            model: "WH-1000XM4".to_string(),
+            vendor: String::new(),
+            serial: "00:11:22:33:44:55".to_string(),
+            percentage: 50.0,
+            state: "discharging".to_string(),
+            matches: true,
+        };
+
+        assert_eq!(
+            table(&[entry]),
+            "PATH                                     NATIVE PATH                            KIND     MODEL       VENDOR  SERIAL             PERCENTAGE  STATE        MATCHES\n\
+             /org/freedesktop/UPower/devices/headset  /org/bluez/hci0/dev_00_11_22_33_44_55  headset  WH-1000XM4          00:11:22:33:44:55  50%         discharging  yes\n"
+        );
+    }
+}
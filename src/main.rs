@@ -1,62 +1,399 @@
-use std::{collections::BTreeSet, str::FromStr};
+use std::{path::PathBuf, str::FromStr};
 
-use clap::Parser;
+mod alias;
+mod bluez;
+mod bus;
+mod config;
+mod cycle;
+mod estimate;
+mod filter;
+mod history;
+mod hooks;
+mod list;
+#[cfg(test)]
+mod memory;
+mod notify;
+mod protocol;
+mod source;
+mod template;
+mod threshold;
+mod transitions;
+mod upower;
+
+use alias::{Alias, AliasRule};
+use bluez::BluezSource;
+use bus::Bus;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use config::Config;
+use cycle::Selection;
+use estimate::Estimator;
+use filter::{Filter, Predicate};
+use history::History;
 use humantime::Duration;
-use num::FromPrimitive;
 use num_derive::FromPrimitive;
-use serde::Serialize;
-use strum::{EnumString, VariantNames};
+use protocol::{Colors, Protocol};
+use serde::{Deserialize, Serialize};
+use source::{Backend, DeviceSource, Event, Tracker};
+use strum::{Display, EnumString, VariantNames};
+use template::Template;
 use textwrap::Options;
-use tokio::select;
-use tokio_stream::StreamExt;
-use upower_dbus::{DeviceProxy, UPowerProxy};
-use zbus::Connection;
+use threshold::{Threshold, Thresholds};
+use tokio::{
+    select,
+    signal::unix::{signal, Signal, SignalKind},
+    time::{sleep_until, Instant},
+};
+use transitions::Reactions;
+use upower::UPowerSource;
 
 #[derive(Debug, Parser)]
 struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration file to read options from.
+    ///
+    /// Defaults to waybar-bluetooth-headphone-battery/config.toml in the XDG config directory,
+    /// which is ignored if it doesn't exist. Keys are the long names of options, and command line
+    /// options take precedence over the file.
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
     /// Bluetooth device kinds to match.
-    #[arg(short, long, default_value = "headset, headphones", long_help = DeviceKindSet::long_help())]
+    #[arg(short, long, global = true, default_value = "headset, headphones", long_help = DeviceKindSet::long_help())]
     kinds: DeviceKindSet,
 
-    /// CSS class returned when the battery percentage is below --low-percentage.
-    #[arg(long, default_value = "low")]
-    low_class: String,
+    /// Only include devices matching at least one of these predicates, such as "model=WH-*".
+    #[arg(
+        long = "match",
+        global = true,
+        value_name = "PREDICATE",
+        long_help = Predicate::long_help("Only include devices matching at least one of these predicates.")
+    )]
+    matches: Vec<Predicate>,
+
+    /// Exclude devices matching any of these predicates, such as "serial=AA:BB:*".
+    #[arg(
+        long,
+        global = true,
+        value_name = "PREDICATE",
+        long_help = Predicate::long_help("Exclude devices matching any of these predicates.")
+    )]
+    exclude: Vec<Predicate>,
+
+    /// Thresholds at or below which a CSS class is included in output, unless the device is
+    /// charging.
+    #[arg(
+        short,
+        long,
+        default_value = "low:20",
+        long_help = "Thresholds at or below which a CSS class is included in output, as a comma \
+                     separated list of CLASS:PERCENTAGE pairs, such as \
+                     \"critical:10,low:20,warning:35\". Only the most severe matching class is \
+                     included, and none are included while the device is charging."
+    )]
+    states: Thresholds,
+
+    /// CSS class included when the battery percentage is at or above a ceiling, such as
+    /// "full:95".
+    #[arg(long, value_name = "CLASS:PERCENTAGE")]
+    full_state: Option<Threshold>,
+
+    /// Deprecated: the class for a single threshold in --states, "low" by default.
+    #[arg(long, hide = true, conflicts_with = "states")]
+    low_class: Option<String>,
 
-    /// The percentage below which --low-class is included in output.
-    #[arg(short, long, default_value = "20")]
-    low_percentage: f64,
+    /// Deprecated: the percentage for a single threshold in --states, 20 by default.
+    #[arg(short, long, hide = true, conflicts_with = "states")]
+    low_percentage: Option<f64>,
 
     /// If set, run continuously.
     #[arg(long)]
     listen: bool,
 
-    /// How frequently to refresh even if there aren't any upower events.
-    #[arg(short, long, default_value = "15s")]
+    /// Where to read devices from: upower, bluez, or auto to use upower if it's available.
+    #[arg(short, long, global = true, default_value = "auto")]
+    backend: Backend,
+
+    /// The D-Bus bus to connect to: system, session, or a bus address.
+    ///
+    /// The system and session buses respect DBUS_SYSTEM_BUS_ADDRESS and DBUS_SESSION_BUS_ADDRESS
+    /// respectively.
+    #[arg(long, global = true, default_value = "system")]
+    bus: Bus,
+
+    /// The status bar protocol to output: waybar, i3bar, i3blocks, polybar, yambar or plain.
+    ///
+    /// i3bar is also used by swaybar. With i3blocks in listen mode, each line is a new full text,
+    /// for use with interval=persist.
+    #[arg(long = "output", default_value = "waybar")]
+    protocol: Protocol,
+
+    /// Colors for CSS classes, for protocols other than waybar, such as "low:#ffaa00".
+    ///
+    /// A comma separated list of CLASS:COLOR pairs. The threshold and --full-state classes take
+    /// precedence over the state class.
+    #[arg(long, default_value = "")]
+    colors: Colors,
+
+    /// How frequently to re-read devices even if there aren't any events.
+    #[arg(short, long, default_value = "5m")]
     refresh: Duration,
+
+    /// If set, re-emit the current output at this interval even if it hasn't changed.
+    ///
+    /// By default, identical lines are only emitted once.
+    #[arg(long)]
+    heartbeat: Option<Duration>,
+
+    /// With --aggregate cycle, also show the next device when SIGRTMIN+N is received.
+    ///
+    /// SIGUSR1 always shows the next device, and SIGUSR2 the previous one.
+    #[arg(long, value_name = "N")]
+    cycle_signal: Option<u8>,
+
+    /// How to combine the percentages of multiple matching devices.
+    #[arg(short, long, default_value = "lowest", long_help = Aggregate::long_help())]
+    aggregate: Aggregate,
+
+    /// Separator used between each device's text when multiple devices match.
+    #[arg(long, default_value = " ")]
+    separator: String,
+
+    /// Format of the text for each device.
+    #[arg(
+        short,
+        long,
+        default_value = "{percentage}%",
+        long_help = Template::long_help("Format of the text for each device.")
+    )]
+    format: Template,
+
+    /// Format of the tooltip line for each device.
+    #[arg(
+        long,
+        default_value = "{name}: {percentage}%",
+        long_help = Template::long_help("Format of the tooltip line for each device.")
+    )]
+    tooltip_format: Template,
+
+    /// A friendly name and icon for a device, such as "WH-1000XM4=Headphones|🎧".
+    ///
+    /// In the form KEY=NAME or KEY=NAME|ICON, where KEY is a serial, Bluetooth address or model.
+    /// Aliases keyed by serial or address take precedence over those keyed by model. The name and
+    /// icon are available as the {name} and {icon} placeholders. May be given multiple times.
+    #[arg(long = "alias", global = true, value_name = "KEY=NAME|ICON")]
+    aliases: Vec<AliasRule>,
+
+    /// What to do when devices can't be read: log, or show.
+    ///
+    /// Errors are always logged to stderr. With log, one-shot mode exits with an error, and listen
+    /// mode keeps retrying while outputting whatever devices can still be read. With show, an
+    /// object with the error class and the error in its tooltip is output instead, and one-shot
+    /// mode exits successfully.
+    #[arg(long, default_value = "log")]
+    on_error: OnError,
+
+    /// Text to output when --on-error is show and an error occurs.
+    #[arg(long, default_value = "error")]
+    error_text: String,
+
+    /// In listen mode, send a desktop notification when a device drops to a threshold in --states.
+    ///
+    /// Notifications are sent on the session bus. The most severe threshold has critical urgency,
+    /// and the others normal urgency. Each device's notification replaces its previous one.
+    #[arg(long)]
+    notify: bool,
+
+    /// In listen mode, send a desktop notification when a device finishes charging.
+    #[arg(long)]
+    notify_charged: bool,
+
+    /// In listen mode, a shell command to run when a device drops to a threshold in --states.
+    ///
+    /// Hook commands are run with sh -c, with the device's fields in environment variables such as
+    /// BATTERY_NAME and BATTERY_PERCENTAGE. Their standard output is discarded, while standard
+    /// error goes to ours.
+    #[arg(long, value_name = "COMMAND")]
+    on_threshold: Option<String>,
+
+    /// In listen mode, a shell command to run when a matching device appears.
+    #[arg(long, value_name = "COMMAND")]
+    on_connect: Option<String>,
+
+    /// In listen mode, a shell command to run when a matching device disappears.
+    #[arg(long, value_name = "COMMAND")]
+    on_disconnect: Option<String>,
+
+    /// In listen mode, a shell command to run when a device finishes charging.
+    #[arg(long, value_name = "COMMAND")]
+    on_charged: Option<String>,
+
+    /// How long hook commands may run before they're killed.
+    #[arg(long, default_value = "30s")]
+    hook_timeout: Duration,
+
+    /// The minimum time between runs of the same hook for the same device, and for
+    /// --on-threshold, the same threshold.
+    #[arg(long, default_value = "1m")]
+    hook_interval: Duration,
+
+    /// Don't record the percentage and state of each device in the history file.
+    ///
+    /// The history is kept in waybar-bluetooth-headphone-battery/history.jsonl in the XDG state
+    /// directory, and is shown by the history subcommand. Without it, {sparkline} only covers the
+    /// current run.
+    #[arg(long)]
+    no_history: bool,
+
+    /// How far back the {sparkline} placeholder goes.
+    #[arg(long, default_value = "12h")]
+    sparkline_window: Duration,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List every device, along with its kind and whether it matches the filter options.
+    List {
+        /// Output JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the recorded history of each device, with its average runtime per full charge and how
+    /// many times it has been charged.
+    History {
+        /// Output JSON instead of text.
+        #[arg(long)]
+        json: bool,
+
+        /// The number of most recent samples to show for each device.
+        #[arg(long, default_value = "20")]
+        samples: usize,
+    },
 }
 
 impl Opt {
-    fn output(&self, percentage: f64, model: &str) -> WaybarOutput {
-        WaybarOutput {
-            text: format!("{percentage}%"),
-            tooltip: Some(model.to_string()),
-            class: if percentage <= self.low_percentage {
-                Some(self.low_class.clone())
-            } else {
-                None
-            },
+    /// Builds the options from the command line, filling in anything not given there from the
+    /// configuration file.
+    fn load(matches: &ArgMatches) -> anyhow::Result<Self> {
+        let mut opt = Self::from_arg_matches(matches)?;
+        Config::load(opt.config.as_deref())?.apply(&mut opt, matches)?;
+        opt.apply_deprecated();
+        Ok(opt)
+    }
+
+    /// Turns --low-class and --low-percentage into the single threshold they used to mean.
+    fn apply_deprecated(&mut self) {
+        if self.low_class.is_none() && self.low_percentage.is_none() {
+            return;
+        }
+        eprintln!("--low-class and --low-percentage are deprecated; use --states instead");
+        self.states = Thresholds::from(Threshold {
+            class: self.low_class.clone().unwrap_or_else(|| "low".to_string()),
+            percentage: self.low_percentage.unwrap_or(20.0),
+        });
+    }
+
+    fn filter(&self) -> Filter {
+        Filter::new(
+            self.kinds.clone(),
+            self.matches.clone(),
+            self.exclude.clone(),
+        )
+    }
+
+    /// Returns the devices with their aliases filled in.
+    fn aliased(&self, devices: &[Device]) -> Vec<Device> {
+        devices
+            .iter()
+            .map(|device| Device {
+                alias: alias::resolve(&self.aliases, device),
+                ..device.clone()
+            })
+            .collect()
+    }
+
+    fn output(&self, devices: &[Device]) -> Option<WaybarOutput> {
+        let devices = self.aliased(devices);
+        let (percentage, representative) = self.aggregate.select(&devices)?;
+
+        let mut class = vec![representative.state.to_string()];
+        if representative.state != DeviceState::Charging {
+            if let Some((_, threshold)) = self.states.matching(percentage) {
+                class.push(threshold.class.clone());
+            }
+        }
+        if let Some(full) = &self.full_state {
+            if percentage >= full.percentage {
+                class.push(full.class.clone());
+            }
+        }
+
+        Some(WaybarOutput {
+            text: devices
+                .iter()
+                .map(|device| self.format.render(device))
+                .collect::<Vec<_>>()
+                .join(&self.separator),
+            alt: Some(representative.state.to_string()),
+            tooltip: Some(
+                devices
+                    .iter()
+                    .map(|device| self.tooltip_format.render(device))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            class,
             percentage: Some(percentage),
+        })
+    }
+
+    fn error_output(&self, error: &anyhow::Error) -> WaybarOutput {
+        WaybarOutput {
+            text: self.error_text.clone(),
+            alt: Some(ERROR_CLASS.to_string()),
+            tooltip: Some(format!("{error:#}")),
+            class: vec![ERROR_CLASS.to_string()],
+            percentage: None,
         }
     }
+
+    /// Formats output in the configured protocol, where `None` means there are no devices.
+    fn render(&self, output: Option<&WaybarOutput>) -> anyhow::Result<String> {
+        self.protocol.format(output, &self.colors, self.listen)
+    }
+
+    /// Logs an error, and also outputs it if configured to do so.
+    fn report(&self, error: &anyhow::Error, emitter: &mut Emitter) -> anyhow::Result<()> {
+        eprintln!("{error:#}");
+        if self.on_error == OnError::Show {
+            emitter.emit(self.render(Some(&self.error_output(error)))?);
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Serialize)]
+/// The CSS class and alt value used when --on-error is show.
+const ERROR_CLASS: &str = "error";
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, EnumString, VariantNames)]
+#[strum(serialize_all = "kebab-case")]
+enum OnError {
+    #[default]
+    Log,
+    Show,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
 struct WaybarOutput {
     text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    tooltip: Option<String>,
+    alt: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    class: Option<String>,
+    tooltip: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    class: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     percentage: Option<f64>,
 }
@@ -73,10 +410,14 @@ struct WaybarOutput {
     PartialOrd,
     Ord,
     FromPrimitive,
+    Display,
     EnumString,
     VariantNames,
+    Serialize,
+    Deserialize,
 )]
 #[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
 enum DeviceKind {
     #[default]
     Unknown = 0,
@@ -111,22 +452,222 @@ enum DeviceKind {
     Last = 29,
 }
 
+#[derive(
+    Default, Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, Display, Serialize, Deserialize,
+)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+enum DeviceState {
+    #[default]
+    Unknown = 0,
+    Charging = 1,
+    Discharging = 2,
+    Empty = 3,
+    FullyCharged = 4,
+    PendingCharge = 5,
+    PendingDischarge = 6,
+}
+
+/// A snapshot of the properties we care about on a single matching device.
+#[derive(Default, Debug, Clone)]
+struct Device {
+    kind: DeviceKind,
+    model: String,
+    vendor: String,
+    serial: String,
+    native_path: String,
+    icon_name: String,
+    percentage: f64,
+    state: DeviceState,
+    time_to_empty: Option<std::time::Duration>,
+    time_to_full: Option<std::time::Duration>,
+    /// Set from --alias when the device is output, rather than by sources.
+    alias: Option<Alias>,
+    /// Estimated from earlier percentages when the device is output, rather than by sources.
+    estimate: Option<std::time::Duration>,
+    /// Drawn from the history when the device is output, rather than by sources.
+    sparkline: Option<String>,
+}
+
+/// Enough of a device to recognise it when it is read again.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Identity {
+    native_path: String,
+    serial: String,
+    model: String,
+}
+
+impl Device {
+    fn identity(&self) -> Identity {
+        Identity {
+            native_path: self.native_path.clone(),
+            serial: self.serial.clone(),
+            model: self.model.clone(),
+        }
+    }
+
+    /// The alias name if there is one, or the model otherwise.
+    fn name(&self) -> &str {
+        match &self.alias {
+            Some(alias) => &alias.name,
+            None => &self.model,
+        }
+    }
+
+    /// The time until the device is empty or, while it's charging, full. The source's own value
+    /// is preferred, since it may know more than the percentage does.
+    fn time_remaining(&self) -> Option<std::time::Duration> {
+        let reported = match self.state {
+            DeviceState::Charging => self.time_to_full,
+            _ => self.time_to_empty,
+        };
+        reported.or(self.estimate)
+    }
+
+    /// The alias icon if there is one, or the icon name reported by the source otherwise.
+    fn icon(&self) -> &str {
+        match self.alias.as_ref().and_then(|alias| alias.icon.as_deref()) {
+            Some(icon) => icon,
+            None => &self.icon_name,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, EnumString, VariantNames)]
+#[strum(serialize_all = "kebab-case")]
+enum Aggregate {
+    /// The lowest percentage of any matching device.
+    #[default]
+    Lowest,
+    /// The highest percentage of any matching device.
+    Highest,
+    /// The mean percentage of all matching devices.
+    Average,
+    /// The percentage of the first device, in the order given to --kinds.
+    First,
+    /// One device at a time, switching to the next on SIGUSR1 and the previous on SIGUSR2.
+    Cycle,
+}
+
+impl Aggregate {
+    /// Returns the aggregated percentage, along with the device whose state should represent the
+    /// whole set. For averages, this is the first device.
+    fn select<'a>(&self, devices: &'a [Device]) -> Option<(f64, &'a Device)> {
+        let device = match self {
+            Self::Lowest => devices
+                .iter()
+                .reduce(|a, b| if b.percentage < a.percentage { b } else { a }),
+            Self::Highest => devices
+                .iter()
+                .reduce(|a, b| if b.percentage > a.percentage { b } else { a }),
+            Self::Average | Self::First | Self::Cycle => devices.first(),
+        }?;
+
+        let percentage = match self {
+            Self::Average => {
+                devices.iter().map(|device| device.percentage).sum::<f64>() / devices.len() as f64
+            }
+            _ => device.percentage,
+        };
+
+        Some((percentage, device))
+    }
+
+    fn long_help() -> String {
+        format!(
+            "How to combine the percentages of multiple matching devices. Possible values: {}.\n\n\
+             The text and tooltip include every matching device; this controls the \
+             percentage, state and CSS class. When averaging, the state is taken from the first \
+             device.\n\n\
+             With cycle, only one device is included at a time. In listen mode, SIGUSR1 switches \
+             to the next device and SIGUSR2 to the previous one, so Waybar's on-click can use \
+             pkill -USR1.",
+            Self::VARIANTS.join(", "),
+        )
+    }
+}
+
+/// The device kinds to match, in priority order.
 #[derive(Clone, Debug)]
-struct DeviceKindSet(BTreeSet<DeviceKind>);
+struct DeviceKindSet(Vec<DeviceKind>);
 
 impl DeviceKindSet {
-    fn contains(&self, kind: DeviceKind) -> bool {
-        self.0.contains(&kind)
+    /// Named groups of kinds that can be used in place of individual kinds.
+    const GROUPS: &'static [(&'static str, &'static [DeviceKind])] = &[
+        (
+            "audio",
+            &[
+                DeviceKind::Headset,
+                DeviceKind::Headphones,
+                DeviceKind::Speakers,
+                DeviceKind::OtherAudio,
+            ],
+        ),
+        (
+            "input",
+            &[
+                DeviceKind::Mouse,
+                DeviceKind::Keyboard,
+                DeviceKind::Touchpad,
+                DeviceKind::GamingInput,
+                DeviceKind::Pen,
+            ],
+        ),
+    ];
+
+    /// Returns the priority of the given kind, where lower is higher priority, or `None` if the
+    /// kind isn't in the set.
+    fn priority(&self, kind: DeviceKind) -> Option<usize> {
+        self.0.iter().position(|candidate| *candidate == kind)
+    }
+
+    /// Expands a single term, which may be a kind, a group, or "all".
+    fn expand(term: &str) -> Result<Vec<DeviceKind>, strum::ParseError> {
+        if term == "all" {
+            return Ok(DeviceKind::VARIANTS
+                .iter()
+                .filter_map(|name| DeviceKind::from_str(name).ok())
+                .filter(|kind| *kind != DeviceKind::Last)
+                .collect());
+        }
+
+        match Self::GROUPS.iter().find(|(name, _)| *name == term) {
+            Some((_, kinds)) => Ok(kinds.to_vec()),
+            None => Ok(vec![DeviceKind::from_str(term)?]),
+        }
     }
 
     fn long_help() -> String {
+        // We have to handle clap indenting here.
+        let options = || Options::new(textwrap::termwidth() - 12);
+
         format!(
-            "Bluetooth device kinds to match, comma separated. Possible values:\n\n{}",
+            "Bluetooth device kinds to match, comma separated, in priority order. Terms are \
+             applied in order, and a term prefixed with ! removes kinds from the set, so \
+             \"all,!battery,!line-power\" matches everything except batteries and power \
+             supplies.\n\nPossible values:\n\n{}\n\nGroups:\n\n{}",
             textwrap::fill(
-                &DeviceKind::VARIANTS.join(", "),
-                // We have to handle clap indenting here.
-                Options::new(textwrap::termwidth() - 12)
+                &std::iter::once("all")
+                    .chain(DeviceKind::VARIANTS.iter().copied())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                options(),
             ),
+            Self::GROUPS
+                .iter()
+                .map(|(name, kinds)| textwrap::fill(
+                    &format!(
+                        "{name}: {}",
+                        kinds
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                    options()
+                ))
+                .collect::<Vec<_>>()
+                .join("\n"),
         )
     }
 }
@@ -135,62 +676,568 @@ impl FromStr for DeviceKindSet {
     type Err = strum::ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut set = BTreeSet::new();
+        let mut kinds: Vec<DeviceKind> = Vec::new();
         for term in s.split(',') {
-            set.insert(DeviceKind::from_str(term.trim())?);
+            let term = term.trim();
+            match term.strip_prefix('!') {
+                Some(excluded) => {
+                    let excluded = Self::expand(excluded.trim())?;
+                    kinds.retain(|kind| !excluded.contains(kind));
+                }
+                None => {
+                    for kind in Self::expand(term)? {
+                        if !kinds.contains(&kind) {
+                            kinds.push(kind);
+                        }
+                    }
+                }
+            }
         }
 
-        Ok(Self(set))
+        Ok(Self(kinds))
     }
 }
 
+/// The initial delay before reconnecting in listen mode, which doubles on each failure.
+const MIN_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The maximum delay before reconnecting in listen mode.
+const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let opt = Opt::try_parse()?;
+    let matches = Opt::command().get_matches();
+    let mut opt = Opt::load(&matches)?;
+    let mut emitter = Emitter::new(opt.heartbeat.map(Into::into));
 
-    let conn = Connection::system().await?;
-    let upower = UPowerProxy::new(&conn).await?;
+    if let Some(Command::History { json, samples }) = opt.command {
+        return history::show(&opt, json, samples);
+    }
+    if let Some(Command::List { json }) = opt.command {
+        let conn = opt.bus.connect().await?;
+        return match opt.backend.resolve(&conn).await? {
+            Backend::Bluez => {
+                list::list(&BluezSource::new(&conn).await?, &opt.filter(), json).await
+            }
+            _ => list::list(&UPowerSource::new(&conn).await?, &opt.filter(), json).await,
+        };
+    }
 
-    output_devices(&opt, &conn, &upower).await?;
+    // Only status output has a header; the subcommands above print plain JSON or text.
+    if let Some(header) = opt.protocol.header() {
+        println!("{header}");
+    }
+    let mut signals = Signals::new(matches, &opt)?;
+    let mut reactions = Reactions::default();
+    let mut estimator = Estimator::default();
+    let mut history = History::load().unwrap_or_else(|e| {
+        // Starting afresh would overwrite whatever is in the file.
+        eprintln!("{e:#}; not recording history");
+        History::default()
+    });
     if opt.listen {
-        let mut ctrl_c = Box::pin(tokio::signal::ctrl_c());
-        let mut signal_stream = upower.receive_all_signals().await?;
+        select! {
+            result = listen(&mut opt, &mut emitter, &mut signals, &mut reactions, &mut estimator, &mut history) => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        }
+    } else {
+        match connect_and_run(
+            &mut opt,
+            &mut emitter,
+            &mut signals,
+            &mut reactions,
+            &mut estimator,
+            &mut history,
+        )
+        .await
+        {
+            Err(e) if opt.on_error == OnError::Show => opt.report(&e, &mut emitter),
+            result => result,
+        }
+    }
+}
 
-        loop {
-            let refresh = tokio::time::sleep(opt.refresh.into());
+/// Runs until interrupted, reconnecting with exponential backoff if the bus or backend can't be
+/// reached.
+async fn listen(
+    opt: &mut Opt,
+    emitter: &mut Emitter,
+    signals: &mut Signals,
+    reactions: &mut Reactions,
+    estimator: &mut Estimator,
+    history: &mut History,
+) -> anyhow::Result<()> {
+    let mut delay = MIN_RETRY_DELAY;
 
-            select! {
-                _signal = signal_stream.next() => output_devices(&opt, &conn, &upower).await?,
-                _time = refresh => output_devices(&opt, &conn, &upower).await?,
-                _ = &mut ctrl_c => break,
-            };
+    loop {
+        let started = Instant::now();
+        let Err(e) = connect_and_run(opt, emitter, signals, reactions, estimator, history).await
+        else {
+            return Ok(());
+        };
+
+        // If we were connected for a while, then this is a new problem, rather than a continuation
+        // of the last one.
+        if started.elapsed() > MAX_RETRY_DELAY {
+            delay = MIN_RETRY_DELAY;
         }
+
+        opt.report(&e, emitter)?;
+        eprintln!("retrying in {}", humantime::format_duration(delay));
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
     }
+}
 
-    Ok(())
+async fn connect_and_run(
+    opt: &mut Opt,
+    emitter: &mut Emitter,
+    signals: &mut Signals,
+    reactions: &mut Reactions,
+    estimator: &mut Estimator,
+    history: &mut History,
+) -> anyhow::Result<()> {
+    let conn = opt.bus.connect().await?;
+    match opt.backend.resolve(&conn).await? {
+        Backend::Bluez => {
+            let source = BluezSource::new(&conn).await?;
+            run(opt, source, emitter, signals, reactions, estimator, history).await
+        }
+        _ => {
+            let source = UPowerSource::new(&conn).await?;
+            run(opt, source, emitter, signals, reactions, estimator, history).await
+        }
+    }
 }
 
-async fn output_devices(
-    opt: &Opt,
-    conn: &Connection,
-    upower: &UPowerProxy<'_>,
+/// Outputs the current devices and, in listen mode, continues to output them as they change.
+///
+/// In listen mode, this only returns if the source can no longer provide events; errors reading
+/// individual devices are logged and otherwise ignored. Options reloaded on SIGHUP apply from the
+/// next output, except for the bus and backend, which apply from the next reconnection.
+async fn run(
+    opt: &mut Opt,
+    source: impl DeviceSource,
+    emitter: &mut Emitter,
+    signals: &mut Signals,
+    reactions: &mut Reactions,
+    estimator: &mut Estimator,
+    history: &mut History,
 ) -> anyhow::Result<()> {
-    let mut devices_seen = 0;
+    let mut tracker = Tracker::new(source, opt.filter());
+    let mut selection = Selection::default();
+
+    tracker.sync().await?;
+    record_devices(opt, &tracker, estimator, history);
+    output_devices(opt, &tracker, &mut selection, estimator, history, emitter)?;
+    if !opt.listen {
+        return Ok(());
+    }
+    // Reconnecting is like the source restarting, in that its devices may take a moment to come
+    // back.
+    let mut settled = reactions.settle();
+    reactions.update(opt, &tracker.devices());
+
+    // Only a sync pushes the next refresh back, so that heartbeats, events and signals can't keep
+    // postponing it.
+    let mut refresh = Instant::now() + opt.refresh.into();
+    let mut failed = false;
+    loop {
+        let heartbeat = emitter.next_heartbeat();
 
-    for device in upower.enumerate_devices().await?.into_iter() {
-        let proxy = DeviceProxy::new(conn, device).await?;
-        let kind = DeviceKind::from_u32(proxy.get_property("Type").await?).unwrap_or_default();
-        let model = proxy.model().await?;
-        if opt.kinds.contains(kind) {
-            devices_seen += 1;
-            let output = opt.output(proxy.percentage().await?, &model);
-            println!("{}", serde_json::to_string(&output)?);
+        let result = select! {
+            event = tracker.next_event() => match event? {
+                Event::Reset => {
+                    settled = reactions.settle();
+                    tracker.apply(Event::Reset).await
+                }
+                event => tracker.apply(event).await,
+            },
+            _settled = sleep_until(settled.unwrap_or_else(Instant::now)), if settled.is_some() => {
+                // Report any devices that didn't come back, unless the source is still failing, in
+                // which case the next successful read will.
+                settled = None;
+                if !failed {
+                    reactions.update(opt, &tracker.devices());
+                }
+                continue;
+            }
+            _time = sleep_until(refresh) => {
+                refresh = Instant::now() + opt.refresh.into();
+                tracker.sync().await
+            }
+            _heartbeat = sleep_until(heartbeat.unwrap_or_else(Instant::now)), if heartbeat.is_some() => {
+                emitter.repeat();
+                continue;
+            }
+            control = signals.next() => match control {
+                Control::Reload(reloaded) => {
+                    // The configuration file can't turn listen mode off or change the protocol once
+                    // output has started.
+                    *opt = Opt {
+                        listen: true,
+                        protocol: opt.protocol,
+                        ..*reloaded
+                    };
+                    emitter.heartbeat = opt.heartbeat.map(Into::into);
+                    tracker.set_filter(opt.filter());
+                    refresh = Instant::now() + opt.refresh.into();
+                    tracker.sync().await
+                }
+                Control::Next => {
+                    selection.step(&tracker.devices(), 1);
+                    Ok(())
+                }
+                Control::Previous => {
+                    selection.step(&tracker.devices(), -1);
+                    Ok(())
+                }
+            },
+        };
+        // After a failed read, the tracker only has whatever could still be read, which is worth
+        // outputting but not reacting to: devices that couldn't be read haven't really
+        // disconnected, and will probably be back once the source recovers.
+        failed = result.is_err();
+        if let Err(e) = result {
+            opt.report(&e.context("error reading devices"), emitter)?;
+            if opt.on_error == OnError::Show {
+                continue;
+            }
+        }
+
+        if !failed {
+            record_devices(opt, &tracker, estimator, history);
         }
+        output_devices(opt, &tracker, &mut selection, estimator, history, emitter)?;
+        if !failed {
+            reactions.update(opt, &tracker.devices());
+        }
+    }
+}
+
+/// Records samples of the current devices, for estimates, sparklines and the history file.
+fn record_devices<S: DeviceSource>(
+    opt: &Opt,
+    tracker: &Tracker<S>,
+    estimator: &mut Estimator,
+    history: &mut History,
+) {
+    let devices = tracker.devices();
+    estimator.record(&devices);
+    if let Err(e) = history.record(&devices, !opt.no_history) {
+        eprintln!("error recording history: {e:#}");
     }
+}
 
-    if devices_seen == 0 {
-        println!();
+fn output_devices<S: DeviceSource>(
+    opt: &Opt,
+    tracker: &Tracker<S>,
+    selection: &mut Selection,
+    estimator: &Estimator,
+    history: &History,
+    emitter: &mut Emitter,
+) -> anyhow::Result<()> {
+    let mut devices = tracker.devices();
+    devices = history.with_sparklines(devices, opt.sparkline_window.into());
+    devices = estimator.estimated(devices);
+    if opt.aggregate == Aggregate::Cycle {
+        devices = selection.select(devices).into_iter().collect();
     }
 
+    emitter.emit(opt.render(opt.output(&devices).as_ref())?);
+
     Ok(())
 }
+
+/// Something requested by a signal in listen mode.
+enum Control {
+    /// SIGHUP was received, and the options were reloaded.
+    Reload(Box<Opt>),
+    /// Show the next device with --aggregate cycle.
+    Next,
+    /// Show the previous device with --aggregate cycle.
+    Previous,
+}
+
+/// The signals handled in listen mode. In one-shot mode, the default handling is left alone.
+struct Signals {
+    matches: ArgMatches,
+    hangup: Option<Signal>,
+    next: Option<Signal>,
+    previous: Option<Signal>,
+    cycle: Option<(u8, Signal)>,
+}
+
+impl Signals {
+    fn new(matches: ArgMatches, opt: &Opt) -> anyhow::Result<Self> {
+        let listen = |kind| -> anyhow::Result<_> {
+            Ok(match opt.listen {
+                true => Some(signal(kind)?),
+                false => None,
+            })
+        };
+
+        let mut signals = Self {
+            matches,
+            hangup: listen(SignalKind::hangup())?,
+            next: listen(SignalKind::user_defined1())?,
+            previous: listen(SignalKind::user_defined2())?,
+            cycle: None,
+        };
+        if opt.listen {
+            signals.set_cycle_signal(opt.cycle_signal)?;
+        }
+
+        Ok(signals)
+    }
+
+    /// Listens for SIGRTMIN+offset, rather than any previous real-time signal.
+    fn set_cycle_signal(&mut self, offset: Option<u8>) -> anyhow::Result<()> {
+        if self.cycle.as_ref().map(|(current, _)| *current) == offset {
+            return Ok(());
+        }
+
+        self.cycle = match offset {
+            Some(offset) => {
+                let number = libc::SIGRTMIN() + i32::from(offset);
+                anyhow::ensure!(
+                    number <= libc::SIGRTMAX(),
+                    "--cycle-signal must be at most {}",
+                    libc::SIGRTMAX() - libc::SIGRTMIN()
+                );
+                Some((offset, signal(SignalKind::from_raw(number))?))
+            }
+            None => None,
+        };
+
+        Ok(())
+    }
+
+    /// Waits for the next signal. If the options reloaded on SIGHUP are invalid, the error is
+    /// logged and the previous options remain in use. This is cancel safe.
+    async fn next(&mut self) -> Control {
+        loop {
+            select! {
+                _ = recv(self.hangup.as_mut()) => {
+                    let reloaded = Opt::load(&self.matches).and_then(|opt| {
+                        self.set_cycle_signal(opt.cycle_signal)?;
+                        Ok(opt)
+                    });
+                    match reloaded {
+                        Ok(opt) => return Control::Reload(Box::new(opt)),
+                        Err(e) => eprintln!("{e:#}; keeping the previous configuration"),
+                    }
+                }
+                _ = recv(self.next.as_mut()) => return Control::Next,
+                _ = recv(self.cycle.as_mut().map(|(_, signal)| signal)) => return Control::Next,
+                _ = recv(self.previous.as_mut()) => return Control::Previous,
+            }
+        }
+    }
+}
+
+/// Waits for a signal, or forever if it isn't being listened for.
+async fn recv(signal: Option<&mut Signal>) {
+    let received = match signal {
+        Some(signal) => signal.recv().await.is_some(),
+        None => false,
+    };
+    if !received {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Writes output lines, suppressing any that are identical to the previous line.
+struct Emitter {
+    last: Option<String>,
+    emitted_at: Instant,
+    heartbeat: Option<std::time::Duration>,
+}
+
+impl Emitter {
+    fn new(heartbeat: Option<std::time::Duration>) -> Self {
+        Self {
+            last: None,
+            emitted_at: Instant::now(),
+            heartbeat,
+        }
+    }
+
+    fn emit(&mut self, line: String) {
+        if self.last.as_ref() != Some(&line) {
+            println!("{line}");
+            self.last = Some(line);
+            self.emitted_at = Instant::now();
+        }
+    }
+
+    /// Re-emits the previous line, if there is one.
+    fn repeat(&mut self) {
+        if let Some(line) = &self.last {
+            println!("{line}");
+            self.emitted_at = Instant::now();
+        }
+    }
+
+    /// Returns when the previous line should be re-emitted, if a heartbeat is configured.
+    fn next_heartbeat(&self) -> Option<Instant> {
+        self.heartbeat.map(|heartbeat| self.emitted_at + heartbeat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opt(args: &[&str]) -> Opt {
+        Opt::try_parse_from(std::iter::once("test").chain(args.iter().copied())).unwrap()
+    }
+
+    fn device(model: &str, percentage: f64, state: DeviceState) -> Device {
+        Device {
+            kind: DeviceKind::Headset,
+            model: model.to_string(),
+            percentage,
+            state,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn kind_set() {
+        let kinds = |s| DeviceKindSet::from_str(s).unwrap().0;
+
+        assert_eq!(
+            kinds("headphones, headset, headphones"),
+            [DeviceKind::Headphones, DeviceKind::Headset]
+        );
+        assert_eq!(
+            kinds("audio,!speakers"),
+            [
+                DeviceKind::Headset,
+                DeviceKind::Headphones,
+                DeviceKind::OtherAudio
+            ]
+        );
+        assert_eq!(kinds("mouse,input,!touchpad,!pen")[0], DeviceKind::Mouse);
+        assert_eq!(kinds("input,!input,pen"), [DeviceKind::Pen]);
+
+        let all = kinds("all,!battery,!line-power");
+        assert_eq!(all.len(), DeviceKind::VARIANTS.len() - 3);
+        assert!(!all.contains(&DeviceKind::Battery));
+        assert!(!all.contains(&DeviceKind::Last));
+        assert!(all.contains(&DeviceKind::Headset));
+
+        assert!(DeviceKindSet::from_str("audio,!nope").is_err());
+    }
+
+    #[test]
+    fn no_devices() {
+        assert_eq!(opt(&[]).output(&[]), None);
+    }
+
+    #[test]
+    fn aggregate() {
+        let devices = [
+            device("a", 60.0, DeviceState::Discharging),
+            device("b", 10.0, DeviceState::Charging),
+            device("c", 80.0, DeviceState::FullyCharged),
+        ];
+
+        let output = opt(&[]).output(&devices).unwrap();
+        assert_eq!(output.text, "60% 10% 80%");
+        assert_eq!(output.tooltip.as_deref(), Some("a: 60%\nb: 10%\nc: 80%"));
+        assert_eq!(output.percentage, Some(10.0));
+        assert_eq!(output.alt.as_deref(), Some("charging"));
+
+        let output = opt(&["-a", "highest", "--separator", "|"])
+            .output(&devices)
+            .unwrap();
+        assert_eq!(output.text, "60%|10%|80%");
+        assert_eq!(output.percentage, Some(80.0));
+        assert_eq!(output.alt.as_deref(), Some("fully-charged"));
+
+        let output = opt(&["-a", "average"]).output(&devices).unwrap();
+        assert_eq!(output.percentage, Some(50.0));
+        assert_eq!(output.alt.as_deref(), Some("discharging"));
+
+        let output = opt(&["-a", "first"]).output(&devices).unwrap();
+        assert_eq!(output.percentage, Some(60.0));
+    }
+
+    #[test]
+    fn classes() {
+        let opt = opt(&["--states", "critical:10,low:20", "--full-state", "full:95"]);
+        let class =
+            |percentage, state| opt.output(&[device("a", percentage, state)]).unwrap().class;
+
+        assert_eq!(
+            class(5.0, DeviceState::Discharging),
+            ["discharging", "critical"]
+        );
+        assert_eq!(
+            class(15.0, DeviceState::Discharging),
+            ["discharging", "low"]
+        );
+        assert_eq!(class(50.0, DeviceState::Discharging), ["discharging"]);
+        assert_eq!(class(15.0, DeviceState::Charging), ["charging"]);
+        assert_eq!(
+            class(100.0, DeviceState::FullyCharged),
+            ["fully-charged", "full"]
+        );
+    }
+
+    #[test]
+    fn deprecated_low_options() {
+        let class = |args: &[&str], percentage| {
+            let mut opt = opt(args);
+            opt.apply_deprecated();
+            opt.output(&[device("a", percentage, DeviceState::Discharging)])
+                .unwrap()
+                .class
+        };
+
+        assert_eq!(class(&["-l", "30"], 25.0), ["discharging", "low"]);
+        assert_eq!(class(&["-l", "30"], 35.0), ["discharging"]);
+        assert_eq!(
+            class(&["--low-class", "warning"], 20.0),
+            ["discharging", "warning"]
+        );
+        assert!(Opt::try_parse_from(["test", "-l", "30", "--states", "low:20"]).is_err());
+    }
+
+    #[test]
+    fn error_output() {
+        let output = opt(&["--error-text", "oops"])
+            .error_output(&anyhow::anyhow!("cause").context("context"));
+        assert_eq!(output.text, "oops");
+        assert_eq!(output.tooltip.as_deref(), Some("context: cause"));
+        assert_eq!(output.class, ["error"]);
+        assert_eq!(output.percentage, None);
+    }
+
+    #[test]
+    fn formats() {
+        let output = opt(&["-f", "{model} {percentage}", "--tooltip-format", "{kind}"])
+            .output(&[device("a", 50.0, DeviceState::Unknown)])
+            .unwrap();
+        assert_eq!(output.text, "a 50");
+        assert_eq!(output.tooltip.as_deref(), Some("headset"));
+    }
+
+    #[test]
+    fn aliases() {
+        let output = opt(&["-f", "{icon}", "--alias", "a=Alpha|α", "--alias", "b=Beta"])
+            .output(&[
+                device("a", 50.0, DeviceState::Unknown),
+                device("b", 40.0, DeviceState::Unknown),
+                device("c", 30.0, DeviceState::Unknown),
+            ])
+            .unwrap();
+        assert_eq!(output.text, "α  ");
+        assert_eq!(
+            output.tooltip.as_deref(),
+            Some("Alpha: 50%\nBeta: 40%\nc: 30%")
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! An in-memory device source, for testing.
+
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    source::{DeviceSource, Event},
+    Device,
+};
+
+type Devices = Arc<Mutex<Vec<(String, Device)>>>;
+
+pub struct MemorySource {
+    devices: Devices,
+    events: UnboundedReceiver<Event>,
+    pub watched: Arc<Mutex<BTreeSet<String>>>,
+}
+
+/// Used to modify the devices in a `MemorySource`, sending the appropriate events.
+pub struct MemoryHandle {
+    devices: Devices,
+    events: UnboundedSender<Event>,
+    pub watched: Arc<Mutex<BTreeSet<String>>>,
+}
+
+pub fn new() -> (MemorySource, MemoryHandle) {
+    let devices = Devices::default();
+    let watched = Arc::new(Mutex::new(BTreeSet::new()));
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    (
+        MemorySource {
+            devices: devices.clone(),
+            events: rx,
+            watched: watched.clone(),
+        },
+        MemoryHandle {
+            devices,
+            events: tx,
+            watched,
+        },
+    )
+}
+
+impl MemoryHandle {
+    pub fn insert(&self, id: &str, device: Device) {
+        self.devices.lock().unwrap().push((id.to_string(), device));
+        self.events.send(Event::Added(id.to_string())).unwrap();
+    }
+
+    pub fn update(&self, id: &str, f: impl FnOnce(&mut Device)) {
+        if let Some((_, device)) = self
+            .devices
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|(candidate, _)| candidate == id)
+        {
+            f(device);
+        }
+        self.events.send(Event::Changed(id.to_string())).unwrap();
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.devices
+            .lock()
+            .unwrap()
+            .retain(|(candidate, _)| candidate != id);
+        self.events.send(Event::Removed(id.to_string())).unwrap();
+    }
+
+    /// Replaces every device without sending individual events, as if the service had restarted.
+    pub fn reset(&self, devices: Vec<(String, Device)>) {
+        *self.devices.lock().unwrap() = devices;
+        self.events.send(Event::Reset).unwrap();
+    }
+}
+
+impl DeviceSource for MemorySource {
+    async fn enumerate(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, _)| id.clone())
+            .collect())
+    }
+
+    async fn snapshot(&self, id: &str) -> anyhow::Result<Option<Device>> {
+        Ok(self
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(candidate, _)| candidate == id)
+            .map(|(_, device)| device.clone()))
+    }
+
+    async fn watch(&mut self, id: &str) -> anyhow::Result<()> {
+        self.watched.lock().unwrap().insert(id.to_string());
+        Ok(())
+    }
+
+    fn unwatch(&mut self, id: &str) {
+        self.watched.lock().unwrap().remove(id);
+    }
+
+    async fn next_event(&mut self) -> anyhow::Result<Event> {
+        match self.events.recv().await {
+            Some(event) => Ok(event),
+            None => std::future::pending().await,
+        }
+    }
+}
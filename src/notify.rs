@@ -0,0 +1,144 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use zbus::{dbus_proxy, zvariant::Value, Connection};
+
+use crate::{bus::Bus, transitions::Transition, Device, Identity, Opt};
+
+#[dbus_proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+}
+
+/// Urgency levels from the desktop notifications specification.
+const LOW: u8 = 0;
+const NORMAL: u8 = 1;
+const CRITICAL: u8 = 2;
+
+/// How long to wait for the notification daemon, which may need to be started first.
+const SEND_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Sends desktop notifications when devices cross thresholds or finish charging.
+///
+/// Notifications are sent from a background task, in order, so that a slow notification daemon
+/// can't hold up output.
+#[derive(Default)]
+pub struct Notifier {
+    /// Started when the first notification is sent.
+    queue: Option<UnboundedSender<Notification>>,
+}
+
+struct Notification {
+    device: Device,
+    summary: String,
+    body: String,
+    urgency: u8,
+}
+
+impl Notifier {
+    /// Queues notifications for any of the given transitions that are enabled. Failing to send a
+    /// notification is logged, rather than treated as an error.
+    pub fn notify(&mut self, opt: &Opt, transitions: &[Transition]) {
+        for transition in transitions.iter().cloned() {
+            let (device, summary, urgency) = match transition {
+                Transition::Crossed {
+                    device,
+                    severity,
+                    class,
+                } if opt.notify => {
+                    let summary = format!("{} battery {class}", device.name());
+                    let urgency = if severity == 0 { CRITICAL } else { NORMAL };
+                    (device, summary, urgency)
+                }
+                Transition::Charged(device) if opt.notify_charged => {
+                    let summary = format!("{} charged", device.name());
+                    (device, summary, LOW)
+                }
+                _ => continue,
+            };
+
+            let queue = self.queue.get_or_insert_with(|| {
+                let (queue, notifications) = mpsc::unbounded_channel();
+                tokio::spawn(Sender::default().run(notifications));
+                queue
+            });
+            // The task only stops if the runtime is shutting down.
+            let _ = queue.send(Notification {
+                body: format!("{}%", device.percentage),
+                device,
+                summary,
+                urgency,
+            });
+        }
+    }
+}
+
+/// Sends queued notifications.
+#[derive(Default)]
+struct Sender {
+    /// Connected when the first notification is sent, and dropped if sending fails.
+    conn: Option<Connection>,
+    /// The most recent notification for each device, which the next one replaces.
+    ids: HashMap<Identity, u32>,
+}
+
+impl Sender {
+    async fn run(mut self, mut notifications: UnboundedReceiver<Notification>) {
+        while let Some(notification) = notifications.recv().await {
+            let result = tokio::time::timeout(SEND_TIMEOUT, self.send(&notification))
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+            if let Err(e) = result {
+                eprintln!("error sending notification: {e:#}");
+                self.conn = None;
+            }
+        }
+    }
+
+    async fn send(&mut self, notification: &Notification) -> anyhow::Result<()> {
+        let Notification {
+            device,
+            summary,
+            body,
+            urgency,
+        } = notification;
+        let conn = match &self.conn {
+            Some(conn) => conn,
+            None => self.conn.insert(Bus::Session.connect().await?),
+        };
+
+        let identity = device.identity();
+        let replaces_id = self.ids.get(&identity).copied().unwrap_or_default();
+        let id = NotificationsProxy::new(conn)
+            .await?
+            .notify(
+                env!("CARGO_PKG_NAME"),
+                replaces_id,
+                &device.icon_name,
+                summary,
+                body,
+                &[],
+                HashMap::from([("urgency", Value::U8(*urgency))]),
+                -1,
+            )
+            .await?;
+        self.ids.insert(identity, id);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use std::{fmt, str::FromStr};
+
+use serde::Serialize;
+use strum::{EnumString, VariantNames};
+
+use crate::WaybarOutput;
+
+/// The block name used in i3bar output, so click events can be attributed to this block.
+const I3BAR_NAME: &str = "bluetooth-battery";
+
+/// The status bar protocol to write output in.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, EnumString, VariantNames)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Protocol {
+    /// A JSON object per line, for Waybar's custom modules with return-type json.
+    #[default]
+    Waybar,
+    /// The i3bar protocol used by i3bar and swaybar, as an infinite JSON array of blocks.
+    I3bar,
+    /// The full text, short text and color lines of an i3blocks block.
+    I3blocks,
+    /// Text with polybar color formatting tags, for custom/script modules.
+    Polybar,
+    /// Transactions of yambar script module tags.
+    Yambar,
+    /// Just the text.
+    Plain,
+}
+
+#[derive(Serialize)]
+struct I3barBlock<'a> {
+    name: &'static str,
+    full_text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<&'a str>,
+}
+
+impl Protocol {
+    /// Returns anything that must be written once, before the first output.
+    pub fn header(self) -> Option<&'static str> {
+        match self {
+            Self::I3bar => Some("{\"version\":1}\n["),
+            _ => None,
+        }
+    }
+
+    /// Formats a single update, where `None` means there are no matching devices.
+    pub fn format(
+        self,
+        output: Option<&WaybarOutput>,
+        colors: &Colors,
+        listen: bool,
+    ) -> anyhow::Result<String> {
+        let text = output
+            .map(|output| output.text.as_str())
+            .unwrap_or_default();
+        let short_text = output
+            .and_then(|output| output.percentage)
+            .map(|percentage| format!("{}%", percentage.round()));
+        let color = output.and_then(|output| colors.matching(&output.class));
+
+        Ok(match self {
+            Self::Waybar => match output {
+                Some(output) => serde_json::to_string(output)?,
+                None => String::new(),
+            },
+            Self::I3bar => {
+                let blocks: Vec<_> = output
+                    .map(|_| I3barBlock {
+                        name: I3BAR_NAME,
+                        full_text: text,
+                        short_text,
+                        color,
+                    })
+                    .into_iter()
+                    .collect();
+                format!("{},", serde_json::to_string(&blocks)?)
+            }
+            // In persistent mode, i3blocks treats each line as a new full text.
+            Self::I3blocks if listen => text.to_string(),
+            Self::I3blocks => [Some(text), short_text.as_deref(), color]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Self::Polybar => match color {
+                Some(color) => format!("%{{F{color}}}{text}%{{F-}}"),
+                None => text.to_string(),
+            },
+            Self::Yambar => {
+                // The last class is the most specific, as for colors: a threshold or
+                // --full-state class if there is one, or else the state or error class.
+                let class = output
+                    .and_then(|output| output.class.last())
+                    .map(String::as_str)
+                    .unwrap_or_default();
+                let percentage = output
+                    .and_then(|output| output.percentage)
+                    .unwrap_or_default()
+                    .round();
+                let state = output
+                    .and_then(|output| output.alt.as_deref())
+                    .unwrap_or_default();
+
+                // Each transaction ends with an empty line.
+                format!(
+                    "text|string|{}\npercentage|range:0-100|{percentage}\nstate|string|{}\n\
+                     class|string|{}\n",
+                    yambar_escape(text),
+                    yambar_escape(state),
+                    yambar_escape(class),
+                )
+            }
+            Self::Plain => text.to_string(),
+        })
+    }
+}
+
+/// Yambar tags are newline delimited, so values can't contain newlines.
+fn yambar_escape(value: &str) -> String {
+    value.replace('\n', " ")
+}
+
+/// Colors for CSS classes, for protocols that can't be styled with CSS.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Colors(Vec<(String, String)>);
+
+impl Colors {
+    /// Returns the color of the last class that has one, so that threshold classes take
+    /// precedence over the state class that comes before them.
+    fn matching(&self, classes: &[String]) -> Option<&str> {
+        classes.iter().rev().find_map(|class| {
+            self.0
+                .iter()
+                .find(|(candidate, _)| candidate == class)
+                .map(|(_, color)| color.as_str())
+        })
+    }
+}
+
+impl FromStr for Colors {
+    type Err = ColorsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|term| !term.trim().is_empty())
+            .map(|term| {
+                let (class, color) = term
+                    .split_once(':')
+                    .ok_or_else(|| ColorsError(term.to_string()))?;
+                let (class, color) = (class.trim(), color.trim());
+                if class.is_empty() || color.is_empty() {
+                    return Err(ColorsError(term.to_string()));
+                }
+
+                Ok((class.to_string(), color.to_string()))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColorsError(String);
+
+impl fmt::Display for ColorsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "color {:?} must be in the form CLASS:COLOR", self.0)
+    }
+}
+
+impl std::error::Error for ColorsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output() -> WaybarOutput {
+        WaybarOutput {
+            text: "15%".to_string(),
+            alt: Some("discharging".to_string()),
+            tooltip: Some("Headset: 15%".to_string()),
+            class: vec!["discharging".to_string(), "low".to_string()],
+            percentage: Some(15.0),
+        }
+    }
+
+    fn format(protocol: Protocol, output: Option<&WaybarOutput>, listen: bool) -> String {
+        let colors = Colors::from_str("low:#ffaa00, discharging:#ffffff").unwrap();
+        protocol.format(output, &colors, listen).unwrap()
+    }
+
+    #[test]
+    fn waybar() {
+        assert_eq!(
+            format(Protocol::Waybar, Some(&output()), true),
+            r#"{"text":"15%","alt":"discharging","tooltip":"Headset: 15%","class":["discharging","low"],"percentage":15.0}"#
+        );
+        assert_eq!(format(Protocol::Waybar, None, true), "");
+    }
+
+    #[test]
+    fn i3bar() {
+        assert_eq!(
+            format(Protocol::I3bar, Some(&output()), true),
+            r##"[{"name":"bluetooth-battery","full_text":"15%","short_text":"15%","color":"#ffaa00"}],"##
+        );
+        assert_eq!(format(Protocol::I3bar, None, true), "[],");
+    }
+
+    #[test]
+    fn i3blocks() {
+        assert_eq!(
+            format(Protocol::I3blocks, Some(&output()), false),
+            "15%\n15%\n#ffaa00"
+        );
+        assert_eq!(format(Protocol::I3blocks, Some(&output()), true), "15%");
+        assert_eq!(format(Protocol::I3blocks, None, false), "");
+    }
+
+    #[test]
+    fn polybar() {
+        assert_eq!(
+            format(Protocol::Polybar, Some(&output()), true),
+            "%{F#ffaa00}15%%{F-}"
+        );
+        assert_eq!(
+            Protocol::Polybar
+                .format(Some(&output()), &Colors::default(), true)
+                .unwrap(),
+            "15%"
+        );
+    }
+
+    #[test]
+    fn yambar() {
+        assert_eq!(
+            format(Protocol::Yambar, Some(&output()), true),
+            "text|string|15%\npercentage|range:0-100|15\nstate|string|discharging\n\
+             class|string|low\n"
+        );
+        assert_eq!(
+            format(Protocol::Yambar, None, true),
+            "text|string|\npercentage|range:0-100|0\nstate|string|\nclass|string|\n"
+        );
+
+        let charging = WaybarOutput {
+            alt: Some("charging".to_string()),
+            class: vec!["charging".to_string()],
+            ..output()
+        };
+        assert!(
+            format(Protocol::Yambar, Some(&charging), true).ends_with("class|string|charging\n")
+        );
+
+        let error = WaybarOutput {
+            text: "error".to_string(),
+            alt: Some("error".to_string()),
+            tooltip: Some("cause".to_string()),
+            class: vec!["error".to_string()],
+            percentage: None,
+        };
+        assert_eq!(
+            format(Protocol::Yambar, Some(&error), true),
+            "text|string|error\npercentage|range:0-100|0\nstate|string|error\n\
+             class|string|error\n"
+        );
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(Colors::from_str(""), Ok(Colors::default()));
+        assert_eq!(Colors::from_str("low"), Err(ColorsError("low".to_string())));
+        assert_eq!(
+            Colors::from_str("low:"),
+            Err(ColorsError("low:".to_string()))
+        );
+    }
+}
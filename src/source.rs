@@ -0,0 +1,356 @@
+use strum::{EnumString, VariantNames};
+use zbus::{fdo, fdo::DBusProxy, names::BusName, Connection};
+
+use crate::{filter::Filter, upower, Device};
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, EnumString, VariantNames)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Backend {
+    /// Use upower if it's available, and BlueZ otherwise.
+    #[default]
+    Auto,
+    Upower,
+    Bluez,
+}
+
+impl Backend {
+    /// Resolves `Auto` to the backend that should actually be used on this connection.
+    pub async fn resolve(self, conn: &Connection) -> anyhow::Result<Self> {
+        Ok(match self {
+            Self::Auto if upower_available(conn).await? => Self::Upower,
+            Self::Auto => Self::Bluez,
+            backend => backend,
+        })
+    }
+}
+
+/// A change reported by a source, identified by the ID of the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Added(String),
+    Removed(String),
+    Changed(String),
+    /// The service behind the source has restarted or gone away, so every device needs to be
+    /// re-read.
+    Reset,
+}
+
+/// Somewhere devices can be discovered and read from.
+///
+/// Device IDs are opaque to everything except the source; for the D-Bus backends, they're object
+/// paths.
+pub trait DeviceSource {
+    /// Returns the ID of every device the source currently knows about.
+    async fn enumerate(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Reads the current state of a device, returning `None` if the device no longer exists or
+    /// doesn't currently have a battery.
+    async fn snapshot(&self, id: &str) -> anyhow::Result<Option<Device>>;
+
+    /// Starts reporting `Event::Changed` for the given device, if the source needs to subscribe
+    /// to each device individually.
+    async fn watch(&mut self, _id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Stops reporting changes for the given device.
+    fn unwatch(&mut self, _id: &str) {}
+
+    /// Re-establishes any subscriptions after the service behind the source has restarted.
+    async fn reset(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Waits for the next event from the source.
+    ///
+    /// This must be cancel safe. An error indicates that no further events can be received, and
+    /// the source should be recreated.
+    async fn next_event(&mut self) -> anyhow::Result<Event>;
+}
+
+/// Tracks the devices from a source that match the configured filter.
+pub struct Tracker<S> {
+    source: S,
+    filter: Filter,
+    devices: Vec<TrackedDevice>,
+}
+
+struct TrackedDevice {
+    id: String,
+    priority: usize,
+    device: Device,
+}
+
+impl<S: DeviceSource> Tracker<S> {
+    pub fn new(source: S, filter: Filter) -> Self {
+        Self {
+            source,
+            filter,
+            devices: Vec::new(),
+        }
+    }
+
+    /// Replaces the filter. This takes effect for each device the next time it is read, so it
+    /// should generally be followed by a sync.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// Re-reads every device from the source.
+    pub async fn sync(&mut self) -> anyhow::Result<()> {
+        let ids = self.source.enumerate().await?;
+
+        let stale: Vec<_> = self
+            .devices
+            .iter()
+            .filter(|tracked| !ids.contains(&tracked.id))
+            .map(|tracked| tracked.id.clone())
+            .collect();
+        for id in stale {
+            self.untrack(&id);
+        }
+
+        for id in ids {
+            self.refresh(id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the next event from the source. This is cancel safe.
+    pub async fn next_event(&mut self) -> anyhow::Result<Event> {
+        self.source.next_event().await
+    }
+
+    pub async fn apply(&mut self, event: Event) -> anyhow::Result<()> {
+        match event {
+            Event::Added(id) | Event::Changed(id) => self.refresh(id).await,
+            Event::Removed(id) => {
+                self.untrack(&id);
+                Ok(())
+            }
+            Event::Reset => {
+                let ids: Vec<_> = self
+                    .devices
+                    .iter()
+                    .map(|tracked| tracked.id.clone())
+                    .collect();
+                for id in ids {
+                    self.untrack(&id);
+                }
+
+                self.source.reset().await?;
+                self.sync().await
+            }
+        }
+    }
+
+    /// Returns the current state of each matching device, in priority order.
+    pub fn devices(&self) -> Vec<Device> {
+        let mut devices: Vec<_> = self.devices.iter().collect();
+
+        // This is a stable sort, so devices of the same kind remain in the order they were found.
+        devices.sort_by_key(|tracked| tracked.priority);
+        devices
+            .into_iter()
+            .map(|tracked| tracked.device.clone())
+            .collect()
+    }
+
+    /// Re-reads a single device, tracking or untracking it as required.
+    async fn refresh(&mut self, id: String) -> anyhow::Result<()> {
+        let Some((priority, device)) = self
+            .source
+            .snapshot(&id)
+            .await?
+            .and_then(|device| Some((self.filter.priority(&device)?, device)))
+        else {
+            self.untrack(&id);
+            return Ok(());
+        };
+
+        match self.devices.iter_mut().find(|tracked| tracked.id == id) {
+            Some(tracked) => {
+                tracked.priority = priority;
+                tracked.device = device;
+            }
+            None => {
+                self.source.watch(&id).await?;
+                self.devices.push(TrackedDevice {
+                    id,
+                    priority,
+                    device,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn untrack(&mut self, id: &str) {
+        if self.devices.iter().any(|tracked| tracked.id == id) {
+            self.devices.retain(|tracked| tracked.id != id);
+            self.source.unwatch(id);
+        }
+    }
+}
+
+/// Returns true if the error indicates that an object or interface doesn't exist, which happens if
+/// a device goes away while we're reading it.
+pub fn is_missing(error: &zbus::Error) -> bool {
+    match error {
+        zbus::Error::FDO(error) => matches!(
+            **error,
+            fdo::Error::UnknownObject(_)
+                | fdo::Error::UnknownInterface(_)
+                | fdo::Error::UnknownMethod(_)
+                | fdo::Error::UnknownProperty(_)
+                | fdo::Error::InvalidArgs(_)
+        ),
+        zbus::Error::MethodError(name, _, _) => matches!(
+            name.as_str(),
+            "org.freedesktop.DBus.Error.UnknownObject"
+                | "org.freedesktop.DBus.Error.UnknownInterface"
+                | "org.freedesktop.DBus.Error.UnknownMethod"
+                | "org.freedesktop.DBus.Error.UnknownProperty"
+                | "org.freedesktop.DBus.Error.InvalidArgs"
+        ),
+        _ => false,
+    }
+}
+
+/// Returns true if upower is running, or can be activated.
+async fn upower_available(conn: &Connection) -> anyhow::Result<bool> {
+    let dbus = DBusProxy::new(conn).await?;
+    let name = BusName::try_from(upower::SERVICE)?;
+
+    Ok(dbus.name_has_owner(name.clone()).await?
+        || dbus
+            .list_activatable_names()
+            .await?
+            .iter()
+            .any(|activatable| activatable.as_str() == upower::SERVICE))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::{memory, DeviceKind, DeviceKindSet};
+
+    fn device(kind: DeviceKind, model: &str, percentage: f64) -> Device {
+        Device {
+            kind,
+            model: model.to_string(),
+            percentage,
+            ..Default::default()
+        }
+    }
+
+    fn models(tracker: &Tracker<memory::MemorySource>) -> Vec<String> {
+        tracker
+            .devices()
+            .into_iter()
+            .map(|device| device.model)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sync_filters_and_orders_by_priority() -> anyhow::Result<()> {
+        let (source, handle) = memory::new();
+        handle.insert("/a", device(DeviceKind::Headset, "headset a", 50.0));
+        handle.insert("/b", device(DeviceKind::Battery, "laptop", 80.0));
+        handle.insert("/c", device(DeviceKind::Headphones, "headphones", 30.0));
+        handle.insert("/d", device(DeviceKind::Headset, "headset d", 70.0));
+
+        let mut tracker = Tracker::new(
+            source,
+            DeviceKindSet::from_str("headphones, headset")?.into(),
+        );
+        tracker.sync().await?;
+
+        assert_eq!(models(&tracker), ["headphones", "headset a", "headset d"]);
+        assert_eq!(
+            handle.watched.lock().unwrap().iter().collect::<Vec<_>>(),
+            ["/a", "/c", "/d"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn events() -> anyhow::Result<()> {
+        let (source, handle) = memory::new();
+        let mut tracker = Tracker::new(source, DeviceKindSet::from_str("headset")?.into());
+        tracker.sync().await?;
+        assert!(tracker.devices().is_empty());
+
+        handle.insert("/a", device(DeviceKind::Headset, "headset", 50.0));
+        handle.insert("/b", device(DeviceKind::Mouse, "mouse", 50.0));
+        for _ in 0..2 {
+            let event = tracker.next_event().await?;
+            tracker.apply(event).await?;
+        }
+        assert_eq!(models(&tracker), ["headset"]);
+        assert!(!handle.watched.lock().unwrap().contains("/b"));
+
+        handle.update("/a", |device| device.percentage = 40.0);
+        let event = tracker.next_event().await?;
+        assert_eq!(event, Event::Changed("/a".to_string()));
+        tracker.apply(event).await?;
+        assert_eq!(tracker.devices()[0].percentage, 40.0);
+
+        handle.remove("/a");
+        let event = tracker.next_event().await?;
+        tracker.apply(event).await?;
+        assert!(tracker.devices().is_empty());
+        assert!(handle.watched.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reset() -> anyhow::Result<()> {
+        let (source, handle) = memory::new();
+        handle.insert("/a", device(DeviceKind::Headset, "old", 50.0));
+
+        let mut tracker = Tracker::new(source, DeviceKindSet::from_str("headset")?.into());
+        tracker.sync().await?;
+        while tracker.next_event().await? != Event::Added("/a".to_string()) {}
+
+        handle.reset(vec![(
+            "/b".to_string(),
+            device(DeviceKind::Headset, "new", 60.0),
+        )]);
+        let event = tracker.next_event().await?;
+        assert_eq!(event, Event::Reset);
+        tracker.apply(event).await?;
+
+        assert_eq!(models(&tracker), ["new"]);
+        assert_eq!(
+            handle.watched.lock().unwrap().iter().collect::<Vec<_>>(),
+            ["/b"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_removes_stale_devices() -> anyhow::Result<()> {
+        let (source, handle) = memory::new();
+        handle.insert("/a", device(DeviceKind::Headset, "headset", 50.0));
+
+        let mut tracker = Tracker::new(source, DeviceKindSet::from_str("headset")?.into());
+        tracker.sync().await?;
+        assert_eq!(models(&tracker), ["headset"]);
+
+        // Removing the device sends an event, but we won't process it, so this tests that sync()
+        // notices that it's gone.
+        handle.remove("/a");
+        tracker.sync().await?;
+        assert!(tracker.devices().is_empty());
+
+        Ok(())
+    }
+}
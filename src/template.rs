@@ -0,0 +1,270 @@
+use std::{fmt, str::FromStr};
+
+use strum::{EnumString, VariantNames};
+
+use crate::Device;
+
+/// A user provided format string, such as `{percentage}% {model}`.
+///
+/// Placeholders are validated when the template is parsed, so rendering can't fail. Literal braces
+/// can be included by doubling them: `{{` and `}}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template(Vec<Segment>);
+
+impl Template {
+    pub fn render(&self, device: &Device) -> String {
+        let mut output = String::new();
+        for segment in self.0.iter() {
+            match segment {
+                Segment::Literal(s) => output.push_str(s),
+                Segment::Placeholder(placeholder) => output.push_str(&placeholder.render(device)),
+            }
+        }
+
+        output
+    }
+
+    pub fn long_help(summary: &str) -> String {
+        format!(
+            "{summary}\n\nAvailable placeholders: {}. Use {{{{ and }}}} for literal braces.",
+            Placeholder::list(),
+        )
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('{') if name.is_empty() => {
+                                literal.push('{');
+                                break;
+                            }
+                            Some('}') => {
+                                let placeholder = Placeholder::from_str(&name)
+                                    .map_err(|_| TemplateError::UnknownPlaceholder(name))?;
+                                if !literal.is_empty() {
+                                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                                }
+                                segments.push(Segment::Placeholder(placeholder));
+                                break;
+                            }
+                            Some(c) => name.push(c),
+                            None => return Err(TemplateError::Unterminated),
+                        }
+                    }
+                }
+                '}' => match chars.next() {
+                    Some('}') => literal.push('}'),
+                    _ => return Err(TemplateError::UnmatchedClose),
+                },
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self(segments))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, VariantNames)]
+#[strum(serialize_all = "snake_case")]
+enum Placeholder {
+    Percentage,
+    Name,
+    Model,
+    Kind,
+    State,
+    TimeToEmpty,
+    TimeRemaining,
+    Icon,
+    Vendor,
+    Sparkline,
+}
+
+impl Placeholder {
+    fn list() -> String {
+        Self::VARIANTS
+            .iter()
+            .map(|name| format!("{{{name}}}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn render(&self, device: &Device) -> String {
+        match self {
+            Self::Percentage => device.percentage.to_string(),
+            Self::Name => device.name().to_string(),
+            Self::Model => device.model.clone(),
+            Self::Kind => device.kind.to_string(),
+            Self::State => device.state.to_string(),
+            Self::TimeToEmpty => device
+                .time_to_empty
+                .map(|duration| humantime::format_duration(duration).to_string())
+                .unwrap_or_default(),
+            Self::TimeRemaining => device
+                .time_remaining()
+                .map(|duration| humantime::format_duration(duration).to_string())
+                .unwrap_or_default(),
+            Self::Icon => device.icon().to_string(),
+            Self::Vendor => device.vendor.clone(),
+            Self::Sparkline => device.sparkline.clone().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    UnknownPlaceholder(String),
+    Unterminated,
+    UnmatchedClose,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownPlaceholder(name) => write!(
+                f,
+                "unknown placeholder {{{name}}}; valid placeholders are: {}",
+                Placeholder::list()
+            ),
+            Self::Unterminated => write!(f, "unterminated placeholder; use {{{{ for a literal {{"),
+            Self::UnmatchedClose => write!(f, "unmatched }}; use }}}} for a literal }}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{alias::Alias, DeviceKind, DeviceState};
+
+    fn device() -> Device {
+        Device {
+            kind: DeviceKind::Headset,
+            model: "WH-1000XM4".to_string(),
+            vendor: "Sony".to_string(),
+            serial: "00:11:22:33:44:55".to_string(),
+            native_path: "/org/bluez/hci0/dev_00_11_22_33_44_55".to_string(),
+            icon_name: "audio-headset".to_string(),
+            percentage: 42.0,
+            state: DeviceState::PendingCharge,
+            time_to_empty: Some(std::time::Duration::from_secs(5400)),
+            time_to_full: None,
+            alias: None,
+            estimate: None,
+            sparkline: None,
+        }
+    }
+
+    #[test]
+    fn render() {
+        let template = Template::from_str(
+            "{percentage}% {model} ({vendor}) {kind} {state} {time_to_empty} {icon}",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&device()),
+            "42% WH-1000XM4 (Sony) headset pending-charge 1h 30m audio-headset"
+        );
+    }
+
+    #[test]
+    fn alias() {
+        let template = Template::from_str("{name} {icon}").unwrap();
+        assert_eq!(template.render(&device()), "WH-1000XM4 audio-headset");
+
+        let device = Device {
+            alias: Some(Alias {
+                name: "Headphones".to_string(),
+                icon: Some("🎧".to_string()),
+            }),
+            ..device()
+        };
+        assert_eq!(template.render(&device), "Headphones 🎧");
+    }
+
+    #[test]
+    fn escapes() {
+        let template = Template::from_str("{{{percentage}}} }}{{").unwrap();
+        assert_eq!(template.render(&device()), "{42} }{");
+    }
+
+    #[test]
+    fn missing_time_to_empty() {
+        let template = Template::from_str("[{time_to_empty}]").unwrap();
+        let device = Device {
+            time_to_empty: None,
+            ..device()
+        };
+        assert_eq!(template.render(&device), "[]");
+    }
+
+    #[test]
+    fn time_remaining() {
+        let template = Template::from_str("[{time_remaining}]").unwrap();
+        let estimate = Some(std::time::Duration::from_secs(600));
+
+        // The source's own value wins over the estimate.
+        let device = Device {
+            estimate,
+            ..device()
+        };
+        assert_eq!(template.render(&device), "[1h 30m]");
+
+        let device = Device {
+            time_to_empty: None,
+            estimate,
+            ..device
+        };
+        assert_eq!(template.render(&device), "[10m]");
+
+        let device = Device {
+            state: DeviceState::Charging,
+            estimate: None,
+            ..device
+        };
+        assert_eq!(template.render(&device), "[]");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Template::from_str("{nope}"),
+            Err(TemplateError::UnknownPlaceholder("nope".to_string()))
+        );
+        assert_eq!(
+            Template::from_str("{percentage"),
+            Err(TemplateError::Unterminated)
+        );
+        assert_eq!(
+            Template::from_str("100%}"),
+            Err(TemplateError::UnmatchedClose)
+        );
+
+        let message = TemplateError::UnknownPlaceholder("nope".to_string()).to_string();
+        assert!(message.contains("{percentage}"));
+        assert!(message.contains("{vendor}"));
+    }
+}
@@ -0,0 +1,142 @@
+use std::{fmt, str::FromStr};
+
+/// A CSS class that applies once a battery percentage crosses a given value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Threshold {
+    pub class: String,
+    pub percentage: f64,
+}
+
+impl FromStr for Threshold {
+    type Err = ThresholdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, percentage) = s
+            .split_once(':')
+            .ok_or_else(|| ThresholdError::MissingSeparator(s.to_string()))?;
+
+        let class = class.trim();
+        if class.is_empty() {
+            return Err(ThresholdError::EmptyClass(s.to_string()));
+        }
+
+        let percentage = percentage.trim();
+        let percentage = f64::from_str(percentage)
+            .map_err(|_| ThresholdError::InvalidPercentage(percentage.to_string()))?;
+
+        Ok(Self {
+            class: class.to_string(),
+            percentage,
+        })
+    }
+}
+
+/// An ordered set of thresholds, modelled on the `states` of Waybar's battery module: the
+/// threshold with the lowest percentage that is still at or above the battery percentage wins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thresholds(Vec<Threshold>);
+
+impl Thresholds {
+    /// Returns the most severe threshold matching the given percentage, along with its severity,
+    /// where 0 is the most severe.
+    pub fn matching(&self, percentage: f64) -> Option<(usize, &Threshold)> {
+        self.0
+            .iter()
+            .enumerate()
+            .find(|(_, threshold)| percentage <= threshold.percentage)
+    }
+}
+
+impl From<Threshold> for Thresholds {
+    fn from(threshold: Threshold) -> Self {
+        Self(vec![threshold])
+    }
+}
+
+impl FromStr for Thresholds {
+    type Err = ThresholdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut thresholds = s
+            .split(',')
+            .filter(|term| !term.trim().is_empty())
+            .map(Threshold::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        thresholds.sort_by(|a, b| a.percentage.total_cmp(&b.percentage));
+        Ok(Self(thresholds))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThresholdError {
+    MissingSeparator(String),
+    EmptyClass(String),
+    InvalidPercentage(String),
+}
+
+impl fmt::Display for ThresholdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingSeparator(term) => {
+                write!(f, "threshold {term:?} must be in the form CLASS:PERCENTAGE")
+            }
+            Self::EmptyClass(term) => write!(f, "threshold {term:?} has an empty class"),
+            Self::InvalidPercentage(percentage) => {
+                write!(f, "invalid threshold percentage {percentage:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ThresholdError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Threshold::from_str(" critical : 10 "),
+            Ok(Threshold {
+                class: "critical".to_string(),
+                percentage: 10.0,
+            })
+        );
+
+        assert_eq!(
+            Threshold::from_str("critical"),
+            Err(ThresholdError::MissingSeparator("critical".to_string()))
+        );
+        assert_eq!(
+            Threshold::from_str(":10"),
+            Err(ThresholdError::EmptyClass(":10".to_string()))
+        );
+        assert_eq!(
+            Threshold::from_str("low:lots"),
+            Err(ThresholdError::InvalidPercentage("lots".to_string()))
+        );
+    }
+
+    #[test]
+    fn most_severe_wins() {
+        let thresholds = Thresholds::from_str("warning:35,critical:10,low:20").unwrap();
+
+        let class = |percentage| {
+            thresholds
+                .matching(percentage)
+                .map(|(severity, threshold)| (severity, threshold.class.as_str()))
+        };
+        assert_eq!(class(5.0), Some((0, "critical")));
+        assert_eq!(class(10.0), Some((0, "critical")));
+        assert_eq!(class(15.0), Some((1, "low")));
+        assert_eq!(class(35.0), Some((2, "warning")));
+        assert_eq!(class(36.0), None);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(Thresholds::from_str("").unwrap().matching(0.0), None);
+    }
+}
@@ -0,0 +1,267 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{
+    hooks::Hooks, notify::Notifier, threshold::Thresholds, Device, DeviceState, Identity, Opt,
+};
+
+/// A change in a device worth telling someone about.
+#[derive(Debug, Clone)]
+pub enum Transition {
+    /// The device dropped to a more severe threshold than it was at before. Severity 0 is the
+    /// most severe threshold.
+    Crossed {
+        device: Device,
+        severity: usize,
+        class: String,
+    },
+    /// The device finished charging.
+    Charged(Device),
+    /// The device appeared after the first update.
+    Connected(Device),
+    /// The device disappeared.
+    Disconnected(Device),
+}
+
+/// How long a source gets to find its devices again after restarting or reconnecting. upower, for
+/// one, claims its bus name before it has added any Bluetooth devices.
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+/// Detects transitions by comparing each set of devices with the previous one.
+#[derive(Debug, Default)]
+pub struct Transitions {
+    previous: HashMap<Identity, Previous>,
+    started: bool,
+    /// Until this time, devices that disappear are assumed to be coming back.
+    settling: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Previous {
+    device: Device,
+    severity: Option<usize>,
+}
+
+impl Transitions {
+    /// Records the current devices, returning any transitions since the previous call.
+    ///
+    /// A device seen for the first time that is already at a threshold counts as having crossed
+    /// it, but not as having finished charging. Devices present at the first update don't count
+    /// as having connected. Thresholds don't apply while a device is charging,
+    /// so a device that charges above a threshold and then drops below it crosses it again.
+    pub fn update(&mut self, thresholds: &Thresholds, devices: &[Device]) -> Vec<Transition> {
+        self.update_at(Instant::now(), thresholds, devices)
+    }
+
+    /// Holds back disconnections until the source has settled, after it has restarted. A device
+    /// that comes back by then carries on from where it was, rather than connecting afresh.
+    /// Returns when the source should have settled, which is when to update again to report any
+    /// devices that didn't come back, or `None` if there are no devices to wait for.
+    pub fn settle(&mut self) -> Option<Instant> {
+        if self.previous.is_empty() {
+            return None;
+        }
+        let deadline = Instant::now() + SETTLE_TIME;
+        self.settling = Some(deadline);
+        Some(deadline)
+    }
+
+    fn update_at(
+        &mut self,
+        now: Instant,
+        thresholds: &Thresholds,
+        devices: &[Device],
+    ) -> Vec<Transition> {
+        let settling = self.settling.is_some_and(|deadline| now < deadline);
+        if !settling {
+            self.settling = None;
+        }
+        let mut transitions = Vec::new();
+        let mut current = HashMap::new();
+
+        for device in devices {
+            let threshold = match device.state {
+                DeviceState::Charging => None,
+                _ => thresholds.matching(device.percentage),
+            };
+            let severity = threshold.map(|(severity, _)| severity);
+            let previous = self.previous.get(&device.identity());
+
+            if let Some((severity, threshold)) = threshold {
+                let crossed = match previous.and_then(|previous| previous.severity) {
+                    Some(previous) => severity < previous,
+                    None => true,
+                };
+                if crossed {
+                    transitions.push(Transition::Crossed {
+                        device: device.clone(),
+                        severity,
+                        class: threshold.class.clone(),
+                    });
+                }
+            }
+
+            match previous {
+                Some(previous)
+                    if device.state == DeviceState::FullyCharged
+                        && previous.device.state != DeviceState::FullyCharged =>
+                {
+                    transitions.push(Transition::Charged(device.clone()));
+                }
+                None if self.started => transitions.push(Transition::Connected(device.clone())),
+                _ => {}
+            }
+
+            current.insert(
+                device.identity(),
+                Previous {
+                    device: device.clone(),
+                    severity,
+                },
+            );
+        }
+
+        let previous = std::mem::replace(&mut self.previous, current);
+        for (identity, previous) in previous {
+            if self.previous.contains_key(&identity) {
+                continue;
+            }
+            if settling {
+                self.previous.insert(identity, previous);
+            } else {
+                transitions.push(Transition::Disconnected(previous.device));
+            }
+        }
+
+        self.started = true;
+        transitions
+    }
+}
+
+/// Everything done in response to transitions in listen mode.
+#[derive(Default)]
+pub struct Reactions {
+    transitions: Transitions,
+    notifier: Notifier,
+    hooks: Hooks,
+}
+
+impl Reactions {
+    /// See `Transitions::settle`.
+    pub fn settle(&mut self) -> Option<Instant> {
+        self.transitions.settle()
+    }
+
+    /// Records the current devices, and reacts to any transitions since the previous update.
+    pub fn update(&mut self, opt: &Opt, devices: &[Device]) {
+        let devices = opt.aliased(devices);
+        let transitions = self.transitions.update(&opt.states, &devices);
+        self.hooks.run(opt, &transitions);
+        self.notifier.notify(opt, &transitions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn device(percentage: f64, state: DeviceState) -> Device {
+        Device {
+            model: "headset".to_string(),
+            percentage,
+            state,
+            ..Default::default()
+        }
+    }
+
+    fn classes(transitions: Vec<Transition>) -> Vec<String> {
+        transitions
+            .into_iter()
+            .map(|transition| match transition {
+                Transition::Crossed {
+                    severity, class, ..
+                } => format!("{class}:{severity}"),
+                Transition::Charged(_) => "charged".to_string(),
+                Transition::Connected(device) => format!("connected {}", device.model),
+                Transition::Disconnected(device) => format!("disconnected {}", device.model),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crossings() {
+        let thresholds = Thresholds::from_str("critical:10,low:20").unwrap();
+        let mut transitions = Transitions::default();
+        let mut update = |percentage, state| {
+            classes(transitions.update(&thresholds, &[device(percentage, state)]))
+        };
+
+        assert!(update(50.0, DeviceState::Discharging).is_empty());
+        assert_eq!(update(20.0, DeviceState::Discharging), ["low:1"]);
+        assert!(update(15.0, DeviceState::Discharging).is_empty());
+        assert_eq!(update(5.0, DeviceState::Discharging), ["critical:0"]);
+        assert!(update(4.0, DeviceState::Discharging).is_empty());
+
+        // Charging resets the thresholds.
+        assert!(update(6.0, DeviceState::Charging).is_empty());
+        assert_eq!(update(6.0, DeviceState::Discharging), ["critical:0"]);
+        assert!(update(30.0, DeviceState::Charging).is_empty());
+        assert_eq!(update(100.0, DeviceState::FullyCharged), ["charged"]);
+        assert!(update(100.0, DeviceState::FullyCharged).is_empty());
+        assert_eq!(update(19.0, DeviceState::Discharging), ["low:1"]);
+    }
+
+    #[test]
+    fn first_sighting() {
+        let thresholds = Thresholds::from_str("low:20").unwrap();
+        let mut transitions = Transitions::default();
+
+        assert_eq!(
+            classes(transitions.update(&thresholds, &[device(10.0, DeviceState::Discharging)])),
+            ["low:0"]
+        );
+        assert_eq!(
+            classes(transitions.update(&thresholds, &[])),
+            ["disconnected headset"]
+        );
+        assert_eq!(
+            classes(transitions.update(&thresholds, &[device(100.0, DeviceState::FullyCharged)])),
+            ["connected headset"]
+        );
+    }
+
+    #[test]
+    fn settling() {
+        let thresholds = Thresholds::from_str("low:20").unwrap();
+        let mut transitions = Transitions::default();
+        let headset = device(10.0, DeviceState::Discharging);
+        let other = Device {
+            model: "other".to_string(),
+            ..device(50.0, DeviceState::Discharging)
+        };
+        transitions.update(&thresholds, &[headset.clone(), other]);
+
+        // While the source settles, a device that comes back hasn't gone anywhere, so it neither
+        // reconnects nor crosses its threshold again.
+        let deadline = transitions.settle().unwrap();
+        let mut update =
+            |now, devices: &[Device]| classes(transitions.update_at(now, &thresholds, devices));
+        assert!(update(deadline - SETTLE_TIME, &[]).is_empty());
+        assert!(update(
+            deadline - Duration::from_secs(1),
+            std::slice::from_ref(&headset)
+        )
+        .is_empty());
+
+        // Once it has settled, one that didn't come back has disconnected.
+        assert_eq!(
+            update(deadline, std::slice::from_ref(&headset)),
+            ["disconnected other"]
+        );
+        assert_eq!(update(deadline, &[]), ["disconnected headset"]);
+        assert_eq!(transitions.settle(), None);
+    }
+}
@@ -0,0 +1,140 @@
+use num::FromPrimitive;
+use tokio::select;
+use tokio_stream::{StreamExt, StreamMap};
+use upower_dbus::{DeviceAddedStream, DeviceProxy, DeviceRemovedStream, UPowerProxy};
+use zbus::{
+    fdo::{DBusProxy, NameOwnerChangedStream, PropertiesChangedStream, PropertiesProxy},
+    zvariant::OwnedObjectPath,
+    CacheProperties, Connection,
+};
+
+use crate::{
+    source::{is_missing, DeviceSource, Event},
+    Device, DeviceKind, DeviceState,
+};
+
+pub const SERVICE: &str = "org.freedesktop.UPower";
+
+/// Reads devices from upower, subscribing to property changes on each watched device.
+pub struct UPowerSource {
+    conn: Connection,
+    upower: UPowerProxy<'static>,
+    added: DeviceAddedStream<'static>,
+    removed: DeviceRemovedStream<'static>,
+    owner_changed: NameOwnerChangedStream<'static>,
+    changes: StreamMap<String, PropertiesChangedStream<'static>>,
+}
+
+impl UPowerSource {
+    pub async fn new(conn: &Connection) -> anyhow::Result<Self> {
+        let upower = UPowerProxy::new(conn).await?;
+        let added = upower.receive_device_added().await?;
+        let removed = upower.receive_device_removed().await?;
+        let owner_changed = DBusProxy::new(conn)
+            .await?
+            .receive_name_owner_changed_with_args(&[(0, SERVICE)])
+            .await?;
+
+        Ok(Self {
+            conn: conn.clone(),
+            upower,
+            added,
+            removed,
+            owner_changed,
+            changes: StreamMap::new(),
+        })
+    }
+}
+
+async fn read_device(proxy: &DeviceProxy<'_>) -> zbus::Result<Device> {
+    Ok(Device {
+        kind: DeviceKind::from_u32(proxy.get_property("Type").await?).unwrap_or_default(),
+        model: proxy.model().await?,
+        vendor: proxy.vendor().await?,
+        serial: proxy.serial().await?,
+        native_path: proxy.native_path().await?,
+        icon_name: proxy.icon_name().await?,
+        percentage: proxy.percentage().await?,
+        state: DeviceState::from_u32(proxy.get_property("State").await?).unwrap_or_default(),
+        time_to_empty: match proxy.get_property::<i64>("TimeToEmpty").await? {
+            seconds if seconds > 0 => Some(std::time::Duration::from_secs(seconds as u64)),
+            _ => None,
+        },
+        time_to_full: match proxy.get_property::<i64>("TimeToFull").await? {
+            seconds if seconds > 0 => Some(std::time::Duration::from_secs(seconds as u64)),
+            _ => None,
+        },
+        alias: None,
+        estimate: None,
+        sparkline: None,
+    })
+}
+
+impl DeviceSource for UPowerSource {
+    async fn enumerate(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .upower
+            .enumerate_devices()
+            .await?
+            .into_iter()
+            .map(|path| path.to_string())
+            .collect())
+    }
+
+    async fn snapshot(&self, id: &str) -> anyhow::Result<Option<Device>> {
+        // Property changes are handled through our own stream, so caching would only risk reading
+        // stale values if we're notified before the proxy's cache is updated.
+        let proxy = DeviceProxy::builder(&self.conn)
+            .path(OwnedObjectPath::try_from(id)?)?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+
+        match read_device(&proxy).await {
+            Ok(device) => Ok(Some(device)),
+            Err(e) if is_missing(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn watch(&mut self, id: &str) -> anyhow::Result<()> {
+        let properties = PropertiesProxy::builder(&self.conn)
+            .destination(SERVICE)?
+            .path(OwnedObjectPath::try_from(id)?)?
+            .build()
+            .await?;
+        self.changes.insert(
+            id.to_string(),
+            properties.receive_properties_changed().await?,
+        );
+
+        Ok(())
+    }
+
+    fn unwatch(&mut self, id: &str) {
+        self.changes.remove(id);
+    }
+
+    async fn reset(&mut self) -> anyhow::Result<()> {
+        self.upower = UPowerProxy::new(&self.conn).await?;
+        self.added = self.upower.receive_device_added().await?;
+        self.removed = self.upower.receive_device_removed().await?;
+        self.changes.clear();
+
+        Ok(())
+    }
+
+    async fn next_event(&mut self) -> anyhow::Result<Event> {
+        select! {
+            Some(signal) = self.added.next() => {
+                Ok(Event::Added(signal.args()?.device.to_string()))
+            }
+            Some(signal) = self.removed.next() => {
+                Ok(Event::Removed(signal.args()?.device.to_string()))
+            }
+            Some(_) = self.owner_changed.next() => Ok(Event::Reset),
+            Some((id, _)) = self.changes.next() => Ok(Event::Changed(id)),
+            else => Err(anyhow::anyhow!("lost connection to upower")),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{Bus, FakeDevice, FakeUPower, Process};
+
+const HEADSET: u32 = 17;
+
+// Points the default buses somewhere that doesn't exist, so the tests only pass if --bus is
+// respected.
+const BOGUS_ENV: &[(&str, &str)] = &[
+    ("DBUS_SYSTEM_BUS_ADDRESS", "unix:path=/nonexistent"),
+    ("DBUS_SESSION_BUS_ADDRESS", "unix:path=/nonexistent"),
+];
+
+#[tokio::test]
+async fn address() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    let mut process =
+        Process::spawn_with_env(&["--backend", "upower", "--bus", &bus.address], BOGUS_ENV)?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn session() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    let mut process = Process::spawn_with_env(
+        &["--backend", "upower", "--bus", "session"],
+        &[
+            ("DBUS_SYSTEM_BUS_ADDRESS", "unix:path=/nonexistent"),
+            ("DBUS_SESSION_BUS_ADDRESS", &bus.address),
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    Ok(())
+}
@@ -0,0 +1,488 @@
+//! Shared harness for integration tests: a private D-Bus daemon, fake upower and notification
+//! services, and a wrapper around the binary under test.
+
+// Each integration test is its own crate, and not every test uses every helper.
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::Value;
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader, Lines},
+    process::{Child, ChildStdout, Command},
+};
+use zbus::{
+    dbus_interface,
+    zvariant::{OwnedObjectPath, OwnedValue},
+    Connection, ConnectionBuilder, SignalContext,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+
+/// A private dbus-daemon, which is killed when dropped.
+pub struct Bus {
+    daemon: Child,
+    pub address: String,
+    _dir: TempDir,
+}
+
+impl Bus {
+    /// Starts a new bus, or returns `None` if dbus-daemon isn't installed.
+    pub async fn start() -> anyhow::Result<Option<Self>> {
+        Self::start_in(tempfile::tempdir()?).await
+    }
+
+    /// Returns the address a bus started with `start_in` will listen on.
+    pub fn address_in(dir: &TempDir) -> String {
+        format!("unix:path={}", dir.path().join("socket").display())
+    }
+
+    /// Starts a new bus listening on a socket in the given directory.
+    pub async fn start_in(dir: TempDir) -> anyhow::Result<Option<Self>> {
+        let config = dir.path().join("bus.conf");
+        std::fs::write(
+            &config,
+            format!(
+                r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>{}</listen>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#,
+                Self::address_in(&dir)
+            ),
+        )?;
+
+        let mut daemon = match Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .arg("--nofork")
+            .arg("--print-address")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("dbus-daemon not found; skipping test");
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut lines = BufReader::new(daemon.stdout.take().unwrap()).lines();
+        let address = tokio::time::timeout(TIMEOUT, lines.next_line())
+            .await??
+            .ok_or_else(|| anyhow::anyhow!("dbus-daemon exited without printing an address"))?;
+
+        Ok(Some(Self {
+            daemon,
+            address,
+            _dir: dir,
+        }))
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = self.daemon.start_kill();
+    }
+}
+
+/// The properties of a fake upower device.
+#[derive(Debug, Clone)]
+pub struct FakeDevice {
+    pub kind: u32,
+    pub model: String,
+    pub percentage: f64,
+    pub state: u32,
+    pub serial: String,
+}
+
+impl FakeDevice {
+    pub fn new(kind: u32, model: &str, percentage: f64) -> Self {
+        Self {
+            kind,
+            model: model.to_string(),
+            percentage,
+            // Discharging.
+            state: 2,
+            serial: String::new(),
+        }
+    }
+}
+
+#[dbus_interface(name = "org.freedesktop.UPower.Device")]
+impl FakeDevice {
+    #[dbus_interface(property, name = "Type")]
+    fn kind(&self) -> u32 {
+        self.kind
+    }
+
+    #[dbus_interface(property)]
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn vendor(&self) -> String {
+        "Vendor".to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn serial(&self) -> String {
+        self.serial.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn native_path(&self) -> String {
+        if self.serial.is_empty() {
+            String::new()
+        } else {
+            format!("/org/bluez/hci0/dev_{}", self.serial.replace(':', "_"))
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn icon_name(&self) -> String {
+        String::new()
+    }
+
+    #[dbus_interface(property)]
+    fn percentage(&self) -> f64 {
+        self.percentage
+    }
+
+    #[dbus_interface(property)]
+    fn state(&self) -> u32 {
+        self.state
+    }
+
+    #[dbus_interface(property)]
+    fn time_to_empty(&self) -> i64 {
+        0
+    }
+
+    #[dbus_interface(property)]
+    fn time_to_full(&self) -> i64 {
+        0
+    }
+}
+
+struct FakeUPowerInterface {
+    devices: Arc<Mutex<Vec<OwnedObjectPath>>>,
+}
+
+#[dbus_interface(name = "org.freedesktop.UPower")]
+impl FakeUPowerInterface {
+    fn enumerate_devices(&self) -> Vec<OwnedObjectPath> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    #[dbus_interface(signal)]
+    async fn device_added(ctxt: &SignalContext<'_>, device: OwnedObjectPath) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn device_removed(ctxt: &SignalContext<'_>, device: OwnedObjectPath) -> zbus::Result<()>;
+}
+
+/// A fake upower service on a private bus.
+pub struct FakeUPower {
+    conn: Connection,
+    devices: Arc<Mutex<Vec<OwnedObjectPath>>>,
+}
+
+impl FakeUPower {
+    pub async fn start(bus: &Bus) -> anyhow::Result<Self> {
+        let devices = Arc::new(Mutex::new(Vec::new()));
+        let conn = ConnectionBuilder::address(bus.address.as_str())?
+            .name("org.freedesktop.UPower")?
+            .serve_at(
+                UPOWER_PATH,
+                FakeUPowerInterface {
+                    devices: devices.clone(),
+                },
+            )?
+            .build()
+            .await?;
+
+        Ok(Self { conn, devices })
+    }
+
+    /// Adds a device, announcing it with DeviceAdded.
+    pub async fn add(&self, name: &str, device: FakeDevice) -> anyhow::Result<OwnedObjectPath> {
+        let path = OwnedObjectPath::try_from(format!("{UPOWER_PATH}/devices/{name}"))?;
+        self.conn.object_server().at(&path, device).await?;
+        self.devices.lock().unwrap().push(path.clone());
+
+        FakeUPowerInterface::device_added(&self.signal_context()?, path.clone()).await?;
+        Ok(path)
+    }
+
+    /// Adds a device to the results of EnumerateDevices without actually creating it, as if it
+    /// disappeared immediately after being enumerated.
+    pub fn add_phantom(&self, name: &str) -> anyhow::Result<()> {
+        let path = OwnedObjectPath::try_from(format!("{UPOWER_PATH}/devices/{name}"))?;
+        self.devices.lock().unwrap().push(path);
+        Ok(())
+    }
+
+    /// Stops the service by releasing its name, as if upower had exited.
+    pub async fn stop(self) -> anyhow::Result<()> {
+        self.conn.release_name("org.freedesktop.UPower").await?;
+        Ok(())
+    }
+
+    /// Removes a device, announcing it with DeviceRemoved.
+    pub async fn remove(&self, path: &OwnedObjectPath) -> anyhow::Result<()> {
+        self.devices.lock().unwrap().retain(|device| device != path);
+        self.conn
+            .object_server()
+            .remove::<FakeDevice, _>(path)
+            .await?;
+
+        FakeUPowerInterface::device_removed(&self.signal_context()?, path.clone()).await?;
+        Ok(())
+    }
+
+    /// Updates a device's percentage, announcing it with PropertiesChanged.
+    pub async fn set_percentage(
+        &self,
+        path: &OwnedObjectPath,
+        percentage: f64,
+    ) -> anyhow::Result<()> {
+        let iface = self
+            .conn
+            .object_server()
+            .interface::<_, FakeDevice>(path)
+            .await?;
+        let mut device = iface.get_mut().await;
+        device.percentage = percentage;
+        device.percentage_changed(iface.signal_context()).await?;
+
+        Ok(())
+    }
+
+    /// Updates a device's percentage without announcing it, so it's only seen when re-read.
+    pub async fn set_percentage_quietly(
+        &self,
+        path: &OwnedObjectPath,
+        percentage: f64,
+    ) -> anyhow::Result<()> {
+        let iface = self
+            .conn
+            .object_server()
+            .interface::<_, FakeDevice>(path)
+            .await?;
+        iface.get_mut().await.percentage = percentage;
+
+        Ok(())
+    }
+
+    /// Updates a device's state, announcing it with PropertiesChanged.
+    pub async fn set_state(&self, path: &OwnedObjectPath, state: u32) -> anyhow::Result<()> {
+        let iface = self
+            .conn
+            .object_server()
+            .interface::<_, FakeDevice>(path)
+            .await?;
+        let mut device = iface.get_mut().await;
+        device.state = state;
+        device.state_changed(iface.signal_context()).await?;
+
+        Ok(())
+    }
+
+    fn signal_context(&self) -> zbus::Result<SignalContext<'_>> {
+        SignalContext::new(&self.conn, UPOWER_PATH)
+    }
+}
+
+/// A notification received by `FakeNotifications`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub replaces_id: u32,
+    pub summary: String,
+    pub body: String,
+    pub urgency: Option<u8>,
+}
+
+struct FakeNotificationsInterface {
+    received: Arc<Mutex<Vec<Notification>>>,
+    delay: Duration,
+}
+
+#[dbus_interface(name = "org.freedesktop.Notifications")]
+impl FakeNotificationsInterface {
+    #[allow(clippy::too_many_arguments)]
+    async fn notify(
+        &self,
+        _app_name: String,
+        replaces_id: u32,
+        _app_icon: String,
+        summary: String,
+        body: String,
+        _actions: Vec<String>,
+        hints: HashMap<String, OwnedValue>,
+        _expire_timeout: i32,
+    ) -> u32 {
+        tokio::time::sleep(self.delay).await;
+        let mut received = self.received.lock().unwrap();
+        received.push(Notification {
+            replaces_id,
+            summary,
+            body,
+            urgency: hints
+                .get("urgency")
+                .and_then(|urgency| u8::try_from(urgency).ok()),
+        });
+
+        // IDs are positive, and this one is only reused if a notification replaces it.
+        match replaces_id {
+            0 => received.len() as u32,
+            id => id,
+        }
+    }
+}
+
+/// A fake notification daemon on a private bus.
+pub struct FakeNotifications {
+    _conn: Connection,
+    received: Arc<Mutex<Vec<Notification>>>,
+    seen: usize,
+}
+
+impl FakeNotifications {
+    pub async fn start(bus: &Bus) -> anyhow::Result<Self> {
+        Self::start_with_delay(bus, Duration::ZERO).await
+    }
+
+    /// Starts a notification daemon that takes the given time to answer each notification.
+    pub async fn start_with_delay(bus: &Bus, delay: Duration) -> anyhow::Result<Self> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let conn = ConnectionBuilder::address(bus.address.as_str())?
+            .name("org.freedesktop.Notifications")?
+            .serve_at(
+                "/org/freedesktop/Notifications",
+                FakeNotificationsInterface {
+                    received: received.clone(),
+                    delay,
+                },
+            )?
+            .build()
+            .await?;
+
+        Ok(Self {
+            _conn: conn,
+            received,
+            seen: 0,
+        })
+    }
+
+    /// Waits for the next notification.
+    pub async fn next(&mut self) -> anyhow::Result<Notification> {
+        tokio::time::timeout(TIMEOUT, async {
+            loop {
+                if let Some(notification) = self.received.lock().unwrap().get(self.seen) {
+                    self.seen += 1;
+                    return notification.clone();
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .map_err(Into::into)
+    }
+
+    /// Returns how many notifications have been received in total.
+    pub fn count(&self) -> usize {
+        self.received.lock().unwrap().len()
+    }
+}
+
+/// The binary under test, running against a private bus.
+pub struct Process {
+    child: Child,
+    lines: Lines<BufReader<ChildStdout>>,
+    /// XDG_STATE_HOME, unless the test gives its own.
+    _state: TempDir,
+}
+
+impl Process {
+    /// Spawns the binary with the private bus as the system bus.
+    pub fn spawn(bus: &Bus, args: &[&str]) -> anyhow::Result<Self> {
+        Self::spawn_with_env(args, &[("DBUS_SYSTEM_BUS_ADDRESS", &bus.address)])
+    }
+
+    pub fn spawn_with_env(args: &[&str], env: &[(&str, &str)]) -> anyhow::Result<Self> {
+        let state = tempfile::tempdir()?;
+        let mut child = Command::new(env!("CARGO_BIN_EXE_waybar-bluetooth-headphone-battery"))
+            .args(args)
+            // Don't pick up the configuration file of whoever is running the tests, or record
+            // history alongside theirs.
+            .env("XDG_CONFIG_HOME", "/nonexistent")
+            .env("XDG_STATE_HOME", state.path())
+            .envs(env.iter().copied())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let lines = BufReader::new(child.stdout.take().unwrap()).lines();
+
+        Ok(Self {
+            child,
+            lines,
+            _state: state,
+        })
+    }
+
+    /// Sends a signal, such as "HUP", to the process.
+    pub async fn signal(&self, name: &str) -> anyhow::Result<()> {
+        let pid = self
+            .child
+            .id()
+            .ok_or_else(|| anyhow::anyhow!("process exited"))?;
+        let status = Command::new("kill")
+            .args(["-s", name, &pid.to_string()])
+            .status()
+            .await?;
+        anyhow::ensure!(status.success(), "kill failed: {status}");
+
+        Ok(())
+    }
+
+    /// Reads the next line, which may be empty.
+    pub async fn next_line(&mut self) -> anyhow::Result<String> {
+        tokio::time::timeout(TIMEOUT, self.lines.next_line())
+            .await??
+            .ok_or_else(|| anyhow::anyhow!("process exited"))
+    }
+
+    /// Reads every remaining line until the process exits.
+    pub async fn remaining_lines(&mut self) -> anyhow::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        while let Some(line) = tokio::time::timeout(TIMEOUT, self.lines.next_line()).await?? {
+            lines.push(line);
+        }
+
+        Ok(lines)
+    }
+
+    /// Reads the next line as JSON.
+    pub async fn next_json(&mut self) -> anyhow::Result<Value> {
+        Ok(serde_json::from_str(&self.next_line().await?)?)
+    }
+}
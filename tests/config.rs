@@ -0,0 +1,109 @@
+mod common;
+
+use common::{Bus, FakeDevice, FakeUPower, Process};
+
+const HEADSET: u32 = 17;
+
+#[tokio::test]
+async fn config_file() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 15.0))
+        .await?;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("config.toml");
+    std::fs::write(
+        &path,
+        r#"
+            backend = "upower"
+            format = "{name} {percentage}"
+            states = ["critical:20"]
+
+            [aliases]
+            Headset = "Work"
+        "#,
+    )?;
+    let path = path.to_str().unwrap();
+
+    let mut process = Process::spawn(&bus, &["--config", path])?;
+    let output = process.next_json().await?;
+    assert_eq!(output["text"], "Work 15");
+    assert_eq!(
+        output["class"],
+        serde_json::json!(["discharging", "critical"])
+    );
+
+    let mut process = Process::spawn(&bus, &["--config", path, "-f", "{percentage}%"])?;
+    assert_eq!(process.next_json().await?["text"], "15%");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_config_file() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("config.toml");
+    std::fs::write(&path, "colour = \"red\"")?;
+
+    let mut process = Process::spawn_with_env(&["--config", path.to_str().unwrap()], &[])?;
+    assert!(process.next_line().await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reload() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let path = upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    let dir = tempfile::tempdir()?;
+    let config = dir.path().join("config.toml");
+    std::fs::write(&config, "format = \"{percentage}%\"")?;
+
+    let mut process = Process::spawn(
+        &bus,
+        &[
+            "--backend",
+            "upower",
+            "--listen",
+            "--config",
+            config.to_str().unwrap(),
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    std::fs::write(&config, "format = \"{model} {percentage}\"")?;
+    process.signal("HUP").await?;
+    assert_eq!(process.next_json().await?["text"], "Headset 50");
+
+    // An invalid configuration is ignored, and the previous one stays in use.
+    std::fs::write(&config, "format = \"{nope}\"")?;
+    process.signal("HUP").await?;
+    upower.set_percentage(&path, 40.0).await?;
+    assert_eq!(process.next_json().await?["text"], "Headset 40");
+
+    Ok(())
+}
+
+#[test]
+fn help() -> anyhow::Result<()> {
+    // Reading the configuration file needs the parsed arguments, which mustn't turn --help into
+    // an error.
+    let output =
+        std::process::Command::new(env!("CARGO_BIN_EXE_waybar-bluetooth-headphone-battery"))
+            .arg("--help")
+            .output()?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)?.starts_with("Usage:"));
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::{Bus, FakeDevice, FakeUPower, Process};
+
+const HEADSET: u32 = 17;
+const HEADPHONES: u32 = 19;
+
+#[tokio::test]
+async fn cycle() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+    let headphones = upower
+        .add(
+            "headphones",
+            FakeDevice::new(HEADPHONES, "Headphones", 90.0),
+        )
+        .await?;
+
+    let mut process = Process::spawn(
+        &bus,
+        &[
+            "--backend",
+            "upower",
+            "--listen",
+            "--aggregate",
+            "cycle",
+            "--cycle-signal",
+            "3",
+        ],
+    )?;
+    let output = process.next_json().await?;
+    assert_eq!(output["text"], "50%");
+    assert_eq!(output["tooltip"], "Headset: 50%");
+
+    process.signal("USR1").await?;
+    assert_eq!(process.next_json().await?["text"], "90%");
+    process.signal("USR2").await?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+    process.signal("RTMIN+3").await?;
+    assert_eq!(process.next_json().await?["text"], "90%");
+
+    // The selection survives changes, and falls back when the device goes away.
+    upower.set_percentage(&headphones, 80.0).await?;
+    assert_eq!(process.next_json().await?["text"], "80%");
+    upower.remove(&headphones).await?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use common::{Bus, FakeDevice, FakeUPower, Process};
+use serde_json::json;
+
+const HEADSET: u32 = 17;
+
+#[tokio::test]
+async fn history() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let mut device = FakeDevice::new(HEADSET, "WH-1000XM4", 50.0);
+    device.serial = "AA:BB:CC:DD:EE:01".to_string();
+    let headset = upower.add("headset", device).await?;
+    // Without a serial or address, this can't be recorded.
+    upower
+        .add("other", FakeDevice::new(HEADSET, "Headset", 80.0))
+        .await?;
+
+    let state = tempfile::tempdir()?;
+    let state = state.path().to_str().unwrap();
+    let env = [
+        ("DBUS_SYSTEM_BUS_ADDRESS", bus.address.as_str()),
+        ("XDG_STATE_HOME", state),
+    ];
+
+    let mut process = Process::spawn_with_env(&["--backend", "upower"], &env)?;
+    process.remaining_lines().await?;
+    upower.set_percentage(&headset, 40.0).await?;
+    let mut process = Process::spawn_with_env(&["--backend", "upower"], &env)?;
+    process.remaining_lines().await?;
+
+    // The sparkline includes samples from earlier runs, but this one isn't recorded.
+    let mut process = Process::spawn_with_env(
+        &[
+            "--backend",
+            "upower",
+            "--no-history",
+            "--match",
+            "model=WH-*",
+            "--tooltip-format",
+            "{sparkline}",
+        ],
+        &env,
+    )?;
+    assert_eq!(process.next_json().await?["tooltip"], "▄ 40–50%");
+
+    let mut process = Process::spawn_with_env(
+        &["history", "--json", "--alias", "WH-1000XM4=Headphones"],
+        &env,
+    )?;
+    let mut output: serde_json::Value =
+        serde_json::from_str(&process.remaining_lines().await?.join("\n"))?;
+    for sample in output[0]["samples"].as_array_mut().unwrap() {
+        sample.as_object_mut().unwrap().remove("time");
+    }
+    assert_eq!(
+        output,
+        json!([{
+            "key": "AA:BB:CC:DD:EE:01",
+            "name": "Headphones",
+            "model": "WH-1000XM4",
+            "kind": "headset",
+            "runtime": null,
+            "charge_cycles": 0,
+            "samples": [
+                {"percentage": 50.0, "state": "discharging"},
+                {"percentage": 40.0, "state": "discharging"},
+            ],
+        }])
+    );
+
+    // Every filter option applies to recorded devices.
+    for (predicate, count) in [
+        ("vendor=Vendor", 1),
+        ("vendor=Other", 0),
+        ("native-path=*dev_AA_BB_CC_DD_EE_01", 1),
+    ] {
+        let mut process =
+            Process::spawn_with_env(&["history", "--json", "--match", predicate], &env)?;
+        let output: serde_json::Value =
+            serde_json::from_str(&process.remaining_lines().await?.join("\n"))?;
+        assert_eq!(output.as_array().map(Vec::len), Some(count), "{predicate}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,187 @@
+mod common;
+
+use std::{path::Path, time::Duration};
+
+use common::{Bus, FakeDevice, FakeUPower, Process};
+
+const HEADSET: u32 = 17;
+
+// Values of upower's State property.
+const CHARGING: u32 = 1;
+const DISCHARGING: u32 = 2;
+
+/// Waits until the hooks have written the given number of lines to the log, and returns them.
+async fn wait_for_lines(log: &Path, count: usize) -> anyhow::Result<Vec<String>> {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let lines: Vec<_> = std::fs::read_to_string(log)
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_string)
+                .collect();
+            if lines.len() >= count {
+                return lines;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("timed out waiting for {count} hook lines"))
+}
+
+#[tokio::test]
+async fn hooks() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let headset = upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    let dir = tempfile::tempdir()?;
+    let log = dir.path().join("hooks.log");
+    let command = format!(
+        r#"echo "$BATTERY_EVENT $BATTERY_NAME $BATTERY_PERCENTAGE $BATTERY_CLASS" >> {}"#,
+        log.display()
+    );
+    let mut process = Process::spawn(
+        &bus,
+        &[
+            "--backend",
+            "upower",
+            "--listen",
+            "--states",
+            "critical:10,low:20",
+            "--on-threshold",
+            &command,
+            "--on-connect",
+            &command,
+            "--on-disconnect",
+            &command,
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    upower.set_percentage(&headset, 20.0).await?;
+    process.next_json().await?;
+    assert_eq!(wait_for_lines(&log, 1).await?, ["threshold Headset 20 low"]);
+
+    // A more severe threshold isn't held back by --hook-interval.
+    upower.set_percentage(&headset, 5.0).await?;
+    process.next_json().await?;
+    assert_eq!(
+        wait_for_lines(&log, 2).await?[1..],
+        ["threshold Headset 5 critical"]
+    );
+
+    // Crossing the same threshold again within --hook-interval is skipped.
+    upower.set_state(&headset, CHARGING).await?;
+    process.next_json().await?;
+    upower.set_state(&headset, DISCHARGING).await?;
+    process.next_json().await?;
+
+    let headphones = upower
+        .add("headphones", FakeDevice::new(HEADSET, "Headphones", 90.0))
+        .await?;
+    process.next_json().await?;
+    assert_eq!(
+        wait_for_lines(&log, 3).await?[2..],
+        ["connect Headphones 90 "]
+    );
+
+    upower.remove(&headphones).await?;
+    process.next_json().await?;
+    assert_eq!(
+        wait_for_lines(&log, 4).await?[3..],
+        ["disconnect Headphones 90 "]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn upower_restart() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let mut device = FakeDevice::new(HEADSET, "Headset", 50.0);
+    device.serial = "AA:BB:CC:DD:EE:01".to_string();
+    upower.add("headset", device.clone()).await?;
+
+    let dir = tempfile::tempdir()?;
+    let log = dir.path().join("hooks.log");
+    let command = format!(r#"echo "$BATTERY_EVENT" >> {}"#, log.display());
+    let mut process = Process::spawn(
+        &bus,
+        &[
+            "--backend",
+            "upower",
+            "--listen",
+            "--on-connect",
+            &command,
+            "--on-disconnect",
+            &command,
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    // The device didn't really disconnect while upower was restarting, even though, like the real
+    // upower, it takes a moment to add devices again.
+    upower.stop().await?;
+    assert_eq!(process.next_line().await?, "");
+    let upower = FakeUPower::start(&bus).await?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    upower.add("headset", device).await?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!log.exists());
+
+    // Disconnecting for real does run the hook.
+    upower.stop().await?;
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("other", FakeDevice::new(HEADSET, "Other", 80.0))
+        .await?;
+    while process.next_line().await?.is_empty() {}
+    let mut lines = wait_for_lines(&log, 2).await?;
+    lines.sort();
+    assert_eq!(lines, ["connect", "disconnect"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timeout() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 10.0))
+        .await?;
+
+    // The subshell outlives the shell that started it, unless it's killed too.
+    let dir = tempfile::tempdir()?;
+    let log = dir.path().join("hooks.log");
+    let command = format!("(sleep 2; echo late >> {}); true", log.display());
+    let mut process = Process::spawn(
+        &bus,
+        &[
+            "--backend",
+            "upower",
+            "--listen",
+            "--on-threshold",
+            &command,
+            "--hook-timeout",
+            "500ms",
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "10%");
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(!log.exists());
+
+    Ok(())
+}
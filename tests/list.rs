@@ -0,0 +1,102 @@
+mod common;
+
+use common::{Bus, FakeDevice, FakeUPower, Process};
+use serde_json::json;
+
+const MOUSE: u32 = 5;
+const HEADSET: u32 = 17;
+
+async fn upower(bus: &Bus) -> anyhow::Result<FakeUPower> {
+    let upower = FakeUPower::start(bus).await?;
+    upower
+        .add(
+            "headset",
+            FakeDevice {
+                serial: "00:11:22:33:44:55".to_string(),
+                ..FakeDevice::new(HEADSET, "Headset", 50.0)
+            },
+        )
+        .await?;
+    upower
+        .add("mouse", FakeDevice::new(MOUSE, "Mouse", 80.0))
+        .await?;
+
+    Ok(upower)
+}
+
+#[tokio::test]
+async fn json() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let _upower = upower(&bus).await?;
+
+    let mut process = Process::spawn(&bus, &["list", "--json", "--backend", "upower"])?;
+    let output: serde_json::Value =
+        serde_json::from_str(&process.remaining_lines().await?.join("\n"))?;
+    assert_eq!(
+        output,
+        json!([
+            {
+                "path": "/org/freedesktop/UPower/devices/headset",
+                "native_path": "/org/bluez/hci0/dev_00_11_22_33_44_55",
+                "kind": "headset",
+                "model": "Headset",
+                "vendor": "Vendor",
+                "serial": "00:11:22:33:44:55",
+                "percentage": 50.0,
+                "state": "discharging",
+                "matches": true,
+            },
+            {
+                "path": "/org/freedesktop/UPower/devices/mouse",
+                "native_path": "",
+                "kind": "mouse",
+                "model": "Mouse",
+                "vendor": "Vendor",
+                "serial": "",
+                "percentage": 80.0,
+                "state": "discharging",
+                "matches": false,
+            },
+        ])
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn json_with_i3bar_output() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let _upower = upower(&bus).await?;
+
+    // The i3bar header is only for status output.
+    let mut process = Process::spawn(
+        &bus,
+        &["--output", "i3bar", "list", "--json", "--backend", "upower"],
+    )?;
+    let output: serde_json::Value =
+        serde_json::from_str(&process.remaining_lines().await?.join("\n"))?;
+    assert_eq!(output.as_array().map(Vec::len), Some(2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn table() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let _upower = upower(&bus).await?;
+
+    let mut process = Process::spawn(&bus, &["list", "--kinds", "mouse", "--backend", "upower"])?;
+    let lines = process.remaining_lines().await?;
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("PATH"));
+    assert!(lines[1].contains("Headset") && lines[1].ends_with("no"));
+    assert!(lines[2].contains("Mouse") && lines[2].ends_with("yes"));
+
+    Ok(())
+}
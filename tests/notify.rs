@@ -0,0 +1,137 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{Bus, FakeDevice, FakeNotifications, FakeUPower, Notification, Process};
+
+const HEADSET: u32 = 17;
+
+// A value of upower's State property.
+const FULLY_CHARGED: u32 = 4;
+
+#[tokio::test]
+async fn notifications() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let headset = upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+    let mut notifications = FakeNotifications::start(&bus).await?;
+
+    let mut process = Process::spawn_with_env(
+        &[
+            "--backend",
+            "upower",
+            "--listen",
+            "--states",
+            "critical:10,low:20",
+            "--alias",
+            "Headset=Work headset",
+            "--notify",
+            "--notify-charged",
+        ],
+        &[
+            ("DBUS_SYSTEM_BUS_ADDRESS", &bus.address),
+            ("DBUS_SESSION_BUS_ADDRESS", &bus.address),
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    upower.set_percentage(&headset, 20.0).await?;
+    process.next_json().await?;
+    assert_eq!(
+        notifications.next().await?,
+        Notification {
+            replaces_id: 0,
+            summary: "Work headset battery low".to_string(),
+            body: "20%".to_string(),
+            urgency: Some(1),
+        }
+    );
+
+    // Staying within the same threshold doesn't notify again.
+    upower.set_percentage(&headset, 15.0).await?;
+    process.next_json().await?;
+
+    upower.set_percentage(&headset, 5.0).await?;
+    process.next_json().await?;
+    let notification = notifications.next().await?;
+    assert_eq!(notification.summary, "Work headset battery critical");
+    assert_eq!(notification.urgency, Some(2));
+    assert_eq!(notification.replaces_id, 1);
+
+    upower.set_percentage(&headset, 100.0).await?;
+    upower.set_state(&headset, FULLY_CHARGED).await?;
+    let notification = notifications.next().await?;
+    assert_eq!(notification.summary, "Work headset charged");
+    assert_eq!(notification.urgency, Some(0));
+    assert_eq!(notifications.count(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn slow_daemon() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let headset = upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+    let _notifications = FakeNotifications::start_with_delay(&bus, Duration::from_secs(60)).await?;
+
+    let mut process = Process::spawn_with_env(
+        &["--backend", "upower", "--listen", "--notify"],
+        &[
+            ("DBUS_SYSTEM_BUS_ADDRESS", &bus.address),
+            ("DBUS_SESSION_BUS_ADDRESS", &bus.address),
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    // Output carries on while the notification is still being sent.
+    upower.set_percentage(&headset, 5.0).await?;
+    assert_eq!(process.next_json().await?["text"], "5%");
+    upower.set_percentage(&headset, 4.0).await?;
+    assert_eq!(process.next_json().await?["text"], "4%");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn upower_restart() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let mut device = FakeDevice::new(HEADSET, "Headset", 15.0);
+    device.serial = "AA:BB:CC:DD:EE:01".to_string();
+    upower.add("headset", device.clone()).await?;
+    let mut notifications = FakeNotifications::start(&bus).await?;
+
+    let mut process = Process::spawn_with_env(
+        &["--backend", "upower", "--listen", "--notify"],
+        &[
+            ("DBUS_SYSTEM_BUS_ADDRESS", &bus.address),
+            ("DBUS_SESSION_BUS_ADDRESS", &bus.address),
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "15%");
+    assert_eq!(notifications.next().await?.summary, "Headset battery low");
+
+    // The device is still at the same threshold once upower is back and has added it again, so
+    // there's nothing new to notify about.
+    upower.stop().await?;
+    assert_eq!(process.next_line().await?, "");
+    let upower = FakeUPower::start(&bus).await?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    upower.add("headset", device).await?;
+    assert_eq!(process.next_json().await?["text"], "15%");
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(notifications.count(), 1);
+
+    Ok(())
+}
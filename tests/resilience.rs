@@ -0,0 +1,178 @@
+mod common;
+
+use common::{Bus, FakeDevice, FakeUPower, Process};
+
+const HEADSET: u32 = 17;
+
+#[tokio::test]
+async fn phantom_device() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower.add_phantom("gone")?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    let mut process = Process::spawn(&bus, &["--backend", "upower"])?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn upower_restart() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    let mut process = Process::spawn(&bus, &["--backend", "upower", "--listen"])?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    upower.stop().await?;
+    assert_eq!(process.next_line().await?, "");
+
+    let upower = FakeUPower::start(&bus).await?;
+    let headset = upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 40.0))
+        .await?;
+    assert_eq!(process.next_json().await?["text"], "40%");
+
+    // Make sure we've subscribed to the device on the new service.
+    upower.set_percentage(&headset, 35.0).await?;
+    assert_eq!(process.next_json().await?["text"], "35%");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn bus_unavailable_at_startup() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let address = Bus::address_in(&dir);
+
+    let mut process =
+        Process::spawn_with_env(&["--backend", "upower", "--listen", "--bus", &address], &[])?;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let Some(bus) = Bus::start_in(dir).await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    // Depending on timing, we might see the service before the device is added.
+    let mut line = process.next_line().await?;
+    if line.is_empty() {
+        line = process.next_line().await?;
+    }
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&line)?["text"],
+        "50%"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn show_errors_one_shot() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let address = Bus::address_in(&dir);
+
+    let mut process = Process::spawn_with_env(
+        &[
+            "--backend",
+            "upower",
+            "--bus",
+            &address,
+            "--on-error",
+            "show",
+            "--error-text",
+            "!",
+        ],
+        &[],
+    )?;
+    let output = process.next_json().await?;
+    assert_eq!(output["text"], "!");
+    assert_eq!(output["class"], serde_json::json!(["error"]));
+    assert!(!output["tooltip"].as_str().unwrap().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn show_errors_listen_bus_unavailable() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let address = Bus::address_in(&dir);
+
+    let mut process = Process::spawn_with_env(
+        &[
+            "--backend",
+            "upower",
+            "--listen",
+            "--bus",
+            &address,
+            "--on-error",
+            "show",
+        ],
+        &[],
+    )?;
+    let output = process.next_json().await?;
+    assert_eq!(output["text"], "error");
+    assert_eq!(output["class"], serde_json::json!(["error"]));
+
+    // Once the bus is back, the error is replaced.
+    let Some(bus) = Bus::start_in(dir).await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+    let mut output = process.next_line().await?;
+    while output.is_empty() || output.contains("error") {
+        output = process.next_line().await?;
+    }
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&output)?["text"],
+        "50%"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn show_errors_listen() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    let mut process = Process::spawn(
+        &bus,
+        &["--backend", "upower", "--listen", "--on-error", "show"],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    upower.stop().await?;
+    let output = process.next_json().await?;
+    assert_eq!(output["text"], "error");
+    assert_eq!(output["alt"], "error");
+
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 40.0))
+        .await?;
+    assert_eq!(process.next_json().await?["text"], "40%");
+
+    Ok(())
+}
@@ -0,0 +1,200 @@
+mod common;
+
+use common::{Bus, FakeDevice, FakeUPower, Process};
+use serde_json::json;
+
+// Values of upower's Type property.
+const MOUSE: u32 = 5;
+const HEADSET: u32 = 17;
+const HEADPHONES: u32 = 19;
+
+#[tokio::test]
+async fn one_shot() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("mouse", FakeDevice::new(MOUSE, "Mouse", 80.0))
+        .await?;
+    upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 15.0))
+        .await?;
+
+    let mut process = Process::spawn(&bus, &["--backend", "upower"])?;
+    assert_eq!(
+        process.next_json().await?,
+        json!({
+            "text": "15%",
+            "alt": "discharging",
+            "tooltip": "Headset: 15%",
+            "class": ["discharging", "low"],
+            "percentage": 15.0,
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn one_shot_no_devices() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    upower
+        .add("mouse", FakeDevice::new(MOUSE, "Mouse", 80.0))
+        .await?;
+
+    let mut process = Process::spawn(&bus, &["--backend", "upower"])?;
+    assert_eq!(process.next_line().await?, "");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn match_and_exclude() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    for (name, model, serial, percentage) in [
+        ("mine", "WH-1000XM4", "AA:BB:CC:DD:EE:01", 60.0),
+        ("theirs", "WH-1000XM4", "AA:BB:CC:DD:EE:02", 10.0),
+        ("other", "Evolve2 65", "AA:BB:CC:DD:EE:03", 30.0),
+    ] {
+        let mut device = FakeDevice::new(HEADSET, model, percentage);
+        device.serial = serial.to_string();
+        upower.add(name, device).await?;
+    }
+
+    let mut process = Process::spawn(
+        &bus,
+        &[
+            "--backend",
+            "upower",
+            "--match",
+            "model=wh-*",
+            "--exclude",
+            "address~:02$",
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "60%");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn listen() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let headset = upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    let mut process = Process::spawn(&bus, &["--backend", "upower", "--listen"])?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    upower.set_percentage(&headset, 45.0).await?;
+    assert_eq!(process.next_json().await?["text"], "45%");
+
+    let headphones = upower
+        .add(
+            "headphones",
+            FakeDevice::new(HEADPHONES, "Headphones", 90.0),
+        )
+        .await?;
+    let output = process.next_json().await?;
+    assert_eq!(output["text"], "45% 90%");
+    assert_eq!(output["tooltip"], "Headset: 45%\nHeadphones: 90%");
+    assert_eq!(output["percentage"], 45.0);
+
+    // Non-matching devices shouldn't cause any output, so the next line must come from the
+    // headphones changing.
+    let mouse = upower
+        .add("mouse", FakeDevice::new(MOUSE, "Mouse", 80.0))
+        .await?;
+    upower.set_percentage(&mouse, 70.0).await?;
+    upower.set_percentage(&headphones, 85.0).await?;
+    assert_eq!(process.next_json().await?["text"], "45% 85%");
+
+    upower.remove(&headset).await?;
+    assert_eq!(process.next_json().await?["text"], "85%");
+
+    upower.remove(&headphones).await?;
+    assert_eq!(process.next_line().await?, "");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn refresh_with_heartbeat() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let headset = upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 50.0))
+        .await?;
+
+    // Heartbeats more frequent than refreshes mustn't stop the refreshes from happening.
+    let mut process = Process::spawn(
+        &bus,
+        &[
+            "--backend",
+            "upower",
+            "--listen",
+            "--refresh",
+            "1s",
+            "--heartbeat",
+            "200ms",
+        ],
+    )?;
+    assert_eq!(process.next_json().await?["text"], "50%");
+
+    upower.set_percentage_quietly(&headset, 40.0).await?;
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while process.next_json().await?["text"] != "40%" {}
+        anyhow::Ok(())
+    })
+    .await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn i3bar() -> anyhow::Result<()> {
+    let Some(bus) = Bus::start().await? else {
+        return Ok(());
+    };
+    let upower = FakeUPower::start(&bus).await?;
+    let headset = upower
+        .add("headset", FakeDevice::new(HEADSET, "Headset", 15.0))
+        .await?;
+
+    let mut process = Process::spawn(
+        &bus,
+        &[
+            "--backend",
+            "upower",
+            "--listen",
+            "--output",
+            "i3bar",
+            "--colors",
+            "low:#ffaa00",
+        ],
+    )?;
+    assert_eq!(process.next_line().await?, r#"{"version":1}"#);
+    assert_eq!(process.next_line().await?, "[");
+    assert_eq!(
+        process.next_line().await?,
+        r##"[{"name":"bluetooth-battery","full_text":"15%","short_text":"15%","color":"#ffaa00"}],"##
+    );
+
+    upower.remove(&headset).await?;
+    assert_eq!(process.next_line().await?, "[],");
+
+    Ok(())
+}